@@ -1,7 +1,9 @@
-use hyper::{Client, client::HttpConnector};
+use std::collections::HashMap;
+
+use hyper::{Client, client::HttpConnector, header::{HeaderName, HeaderValue}};
 use hyper_tls::HttpsConnector;
 
-use crate::parameters::{str_to_filter, OversizedImageHandling};
+use crate::parameters::{str_to_filter, str_to_dpr, str_to_quality, str_to_compression, str_to_png_filter, Gravity, ImageParameters, OversizedImageHandling};
 
 #[derive(Debug)]
 #[derive(PartialEq)]
@@ -28,27 +30,130 @@ impl PartialEq for ImgSource {
     }
 }
 
+// Whether a Folder source serves files reached through symlinks
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum SymlinkPolicy {
+  Never,
+  WithinRoot,
+  Always
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = std::fmt::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SymlinkPolicy::Never),
+            "within_root" => Ok(SymlinkPolicy::WithinRoot),
+            "always" => Ok(SymlinkPolicy::Always),
+            _ => Err(std::fmt::Error)
+        }
+    }
+}
+
 #[derive(Clone)]
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub struct ImgprssrConfig {
   pub default_filter: image::imageops::FilterType,
   pub default_oversize_handling: OversizedImageHandling,
-  pub image_source: ImgSource
+  pub image_source: ImgSource,
+  pub negotiate_format: bool,
+  pub client_hints: bool,
+  pub auto_orient: bool,
+  pub default_quality: Option<u8>,
+  pub default_lossless: bool,
+  pub default_png_compression: image::codecs::png::CompressionType,
+  pub default_png_filter: image::codecs::png::FilterType,
+  pub max_dpr: f32,
+  pub watermark_image: Option<String>,
+  pub watermark_path_prefixes: Vec<String>,
+  pub watermark_position: Gravity,
+  pub watermark_margin: u32,
+  pub watermark_opacity: f32,
+  pub watermark_scale: f32,
+  pub watermark_min_size: u32,
+  pub presets: HashMap<String, String>,
+  pub signing_key: Option<String>,
+  pub allowed_widths: Vec<u32>,
+  pub allowed_heights: Vec<u32>,
+  pub size_bucketing: bool,
+  pub max_source_width: u32,
+  pub max_source_height: u32,
+  pub max_source_pixels: u64,
+  pub max_source_bytes: u64,
+  pub max_output_width: u32,
+  pub max_output_height: u32,
+  pub follow_symlinks: SymlinkPolicy,
+  pub upstream_timeout: u64,
+  pub max_redirects: u32,
+  pub cross_host_redirects: bool,
+  pub cache_control: String,
+  pub error_cache_control: String,
+  pub extra_headers: Vec<(HeaderName, HeaderValue)>
 }
 
-impl ImgprssrConfig {
-  pub fn default() -> ImgprssrConfig {
+impl Default for ImgprssrConfig {
+  fn default() -> ImgprssrConfig {
     ImgprssrConfig {
       default_filter: image::imageops::FilterType::Nearest,
       default_oversize_handling: OversizedImageHandling::Clamp,
-      image_source: ImgSource::Folder("./images".to_owned())
+      image_source: ImgSource::Folder("./images".to_owned()),
+      negotiate_format: false,
+      client_hints: false,
+      auto_orient: true,
+      default_quality: None,
+      default_lossless: true,
+      default_png_compression: image::codecs::png::CompressionType::Fast,
+      default_png_filter: image::codecs::png::FilterType::Adaptive,
+      max_dpr: 3.0,
+      watermark_image: None,
+      watermark_path_prefixes: vec![],
+      watermark_position: Gravity::SouthEast,
+      watermark_margin: 10,
+      watermark_opacity: 0.5,
+      watermark_scale: 0.2,
+      watermark_min_size: 0,
+      presets: HashMap::new(),
+      signing_key: None,
+      allowed_widths: vec![],
+      allowed_heights: vec![],
+      size_bucketing: false,
+      max_source_width: 16384,
+      max_source_height: 16384,
+      max_source_pixels: 100_000_000,
+      max_source_bytes: 50 * 1024 * 1024,
+      max_output_width: 8192,
+      max_output_height: 8192,
+      follow_symlinks: SymlinkPolicy::WithinRoot,
+      upstream_timeout: 30,
+      max_redirects: 5,
+      cross_host_redirects: false,
+      cache_control: "public, max-age=86400".to_owned(),
+      error_cache_control: "no-store".to_owned(),
+      extra_headers: vec![]
     }
   }
 }
 
+const PRESET_KEY_PREFIX: &str = "preset_";
+const HEADER_KEY_PREFIX: &str = "header_";
+
+// Comma separated sizes in pixels, sorted for bucketing
+fn str_to_sizes(sizes_string: &str) -> Option<Vec<u32>> {
+  let mut sizes = sizes_string.split(',')
+    .map(|size| size.trim().parse::<u32>().ok().filter(|size| *size > 0))
+    .collect::<Option<Vec<u32>>>()?;
+  sizes.sort_unstable();
+  sizes.dedup();
+  Some(sizes)
+}
+
 // Really need to look at how to do this with a trait
-pub fn from_hashmap(hmp: std::collections::HashMap<String, String>) -> Result<ImgprssrConfig, ImgprssrConfigErr> {
+pub fn from_hashmap(hmp: HashMap<String, String>) -> Result<ImgprssrConfig, ImgprssrConfigErr> {
   let mut config = ImgprssrConfig::default();
   let mut errors: Vec<String> = vec![];
   if let Some(val) = hmp.get("default_filter") {
@@ -57,6 +162,12 @@ pub fn from_hashmap(hmp: std::collections::HashMap<String, String>) -> Result<Im
         Err(_) => errors.push(format!("default_filter::{val}")),
     }
   }
+  if let Some(val) = hmp.get("default_oversize_handling") {
+    match val.parse::<OversizedImageHandling>() {
+        Ok(handling) => config.default_oversize_handling = handling,
+        Err(_) => errors.push(format!("default_oversize_handling::{val}")),
+    }
+  }
   if let Some(img_src) = hmp.get("image_source") {
     // TODO: We should actually validate these values
     if img_src.starts_with("https://") {
@@ -69,7 +180,211 @@ pub fn from_hashmap(hmp: std::collections::HashMap<String, String>) -> Result<Im
       config.image_source = ImgSource::Folder(img_src.to_owned());
     }
   }
-  if errors.len() > 0 {
+  if let Some(val) = hmp.get("negotiate_format") {
+    match val.parse::<bool>() {
+        Ok(negotiate) => config.negotiate_format = negotiate,
+        Err(_) => errors.push(format!("negotiate_format::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("client_hints") {
+    match val.parse::<bool>() {
+        Ok(client_hints) => config.client_hints = client_hints,
+        Err(_) => errors.push(format!("client_hints::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("auto_orient") {
+    match val.parse::<bool>() {
+        Ok(auto_orient) => config.auto_orient = auto_orient,
+        Err(_) => errors.push(format!("auto_orient::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("default_quality") {
+    match str_to_quality(val) {
+        Ok(quality) => config.default_quality = Some(quality),
+        Err(_) => errors.push(format!("default_quality::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("default_lossless") {
+    match val.parse::<bool>() {
+        Ok(lossless) => config.default_lossless = lossless,
+        Err(_) => errors.push(format!("default_lossless::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("default_png_compression") {
+    match str_to_compression(val) {
+        Ok(compression) => config.default_png_compression = compression,
+        Err(_) => errors.push(format!("default_png_compression::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("default_png_filter") {
+    match str_to_png_filter(val) {
+        Ok(fltr) => config.default_png_filter = fltr,
+        Err(_) => errors.push(format!("default_png_filter::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("max_dpr") {
+    match str_to_dpr(val) {
+        Ok(dpr) => config.max_dpr = dpr,
+        Err(_) => errors.push(format!("max_dpr::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("watermark_image") {
+    if val.starts_with('/') {
+      config.watermark_image = Some(val.to_owned());
+    } else {
+      errors.push(format!("watermark_image::{val}"));
+    }
+  }
+  if let Some(val) = hmp.get("watermark_path_prefixes") {
+    config.watermark_path_prefixes = val.split(',')
+      .map(|prefix| prefix.trim().to_owned())
+      .filter(|prefix| !prefix.is_empty())
+      .collect();
+  }
+  if let Some(val) = hmp.get("watermark_position") {
+    match val.parse::<Gravity>() {
+        Ok(Gravity::Smart) | Err(_) => errors.push(format!("watermark_position::{val}")),
+        Ok(position) => config.watermark_position = position,
+    }
+  }
+  if let Some(val) = hmp.get("watermark_margin") {
+    match val.parse::<u32>() {
+        Ok(margin) => config.watermark_margin = margin,
+        Err(_) => errors.push(format!("watermark_margin::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("watermark_opacity") {
+    match val.parse::<f32>() {
+        Ok(opacity) if (0.0..=1.0).contains(&opacity) => config.watermark_opacity = opacity,
+        _ => errors.push(format!("watermark_opacity::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("watermark_scale") {
+    match val.parse::<f32>() {
+        Ok(scale) if scale > 0.0 && scale <= 1.0 => config.watermark_scale = scale,
+        _ => errors.push(format!("watermark_scale::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("watermark_min_size") {
+    match val.parse::<u32>() {
+        Ok(min_size) => config.watermark_min_size = min_size,
+        Err(_) => errors.push(format!("watermark_min_size::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("signing_key") {
+    // Don't echo the key back in the error
+    if val.is_empty() {
+      errors.push("signing_key::".to_owned());
+    } else {
+      config.signing_key = Some(val.to_owned());
+    }
+  }
+  // Client hints are unsigned headers that pick the output size, so
+  // they'd let anyone around the variants a signature pins down
+  if config.signing_key.is_some() && config.client_hints {
+    errors.push("client_hints::true".to_owned());
+  }
+  for (key, sizes) in [("allowed_widths", &mut config.allowed_widths), ("allowed_heights", &mut config.allowed_heights)] {
+    if let Some(val) = hmp.get(key) {
+      match str_to_sizes(val) {
+          Some(parsed) => *sizes = parsed,
+          None => errors.push(format!("{key}::{val}")),
+      }
+    }
+  }
+  if let Some(val) = hmp.get("size_bucketing") {
+    match val.parse::<bool>() {
+        Ok(bucketing) => config.size_bucketing = bucketing,
+        Err(_) => errors.push(format!("size_bucketing::{val}")),
+    }
+  }
+  for (key, limit) in [
+    ("max_source_width", &mut config.max_source_width),
+    ("max_source_height", &mut config.max_source_height),
+    ("max_output_width", &mut config.max_output_width),
+    ("max_output_height", &mut config.max_output_height)
+  ] {
+    if let Some(val) = hmp.get(key) {
+      match val.parse::<u32>() {
+          Ok(parsed) if parsed > 0 => *limit = parsed,
+          _ => errors.push(format!("{key}::{val}")),
+      }
+    }
+  }
+  if let Some(val) = hmp.get("max_source_pixels") {
+    match val.parse::<u64>() {
+        Ok(pixels) if pixels > 0 => config.max_source_pixels = pixels,
+        _ => errors.push(format!("max_source_pixels::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("max_source_bytes") {
+    match val.parse::<u64>() {
+        Ok(bytes) if bytes > 0 => config.max_source_bytes = bytes,
+        _ => errors.push(format!("max_source_bytes::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("follow_symlinks") {
+    match val.parse::<SymlinkPolicy>() {
+        Ok(policy) => config.follow_symlinks = policy,
+        Err(_) => errors.push(format!("follow_symlinks::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("upstream_timeout") {
+    match val.parse::<u64>() {
+        Ok(timeout) if timeout > 0 => config.upstream_timeout = timeout,
+        _ => errors.push(format!("upstream_timeout::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("max_redirects") {
+    match val.parse::<u32>() {
+        Ok(redirects) => config.max_redirects = redirects,
+        Err(_) => errors.push(format!("max_redirects::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("cross_host_redirects") {
+    match val.parse::<bool>() {
+        Ok(cross_host_redirects) => config.cross_host_redirects = cross_host_redirects,
+        Err(_) => errors.push(format!("cross_host_redirects::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("cache_control") {
+    match HeaderValue::from_str(val) {
+        Ok(_) => config.cache_control = val.to_owned(),
+        Err(_) => errors.push(format!("cache_control::{val}")),
+    }
+  }
+  if let Some(val) = hmp.get("error_cache_control") {
+    match HeaderValue::from_str(val) {
+        Ok(_) => config.error_cache_control = val.to_owned(),
+        Err(_) => errors.push(format!("error_cache_control::{val}")),
+    }
+  }
+  // Every "header_<name>" is sent with every response, with underscores
+  // in the name standing in for the dashes environment variables can't hold
+  let mut header_keys: Vec<&String> = hmp.keys().filter(|key| key.starts_with(HEADER_KEY_PREFIX)).collect();
+  header_keys.sort();
+  for key in header_keys {
+    let name = key[HEADER_KEY_PREFIX.len()..].replace('_', "-");
+    let val = &hmp[key];
+    match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(val)) {
+        (Ok(name), Ok(val)) => config.extra_headers.push((name, val)),
+        _ => errors.push(format!("{key}::{val}")),
+    }
+  }
+  // Every "preset_<name>" holds a query string, checked now so a typo
+  // fails at startup rather than on each request
+  let mut preset_keys: Vec<&String> = hmp.keys().filter(|key| key.starts_with(PRESET_KEY_PREFIX)).collect();
+  preset_keys.sort();
+  for key in preset_keys {
+    let name = &key[PRESET_KEY_PREFIX.len()..];
+    let val = &hmp[key];
+    if name.is_empty() || name.contains('/') || ImageParameters::from_query(val, &config).is_err() {
+      errors.push(format!("{key}::{val}"));
+    } else {
+      config.presets.insert(name.to_owned(), val.to_owned());
+    }
+  }
+  if !errors.is_empty() {
     return Err(ImgprssrConfigErr::InvalidValues(errors));
   }
   Ok(config)
@@ -77,8 +392,6 @@ pub fn from_hashmap(hmp: std::collections::HashMap<String, String>) -> Result<Im
 
 #[cfg(test)]
 mod tests {
-  use std::collections::HashMap;
-
   use super::*;
 
   #[test]
@@ -105,6 +418,31 @@ mod tests {
   }
 
 
+  #[test]
+  fn valid_oversize_handling_parsed() {
+    let cases = [
+      (OversizedImageHandling::Clamp, "clamp"),
+      (OversizedImageHandling::Upscale, "upscale"),
+      (OversizedImageHandling::Reject, "reject"),
+    ];
+    for (handling, handling_string) in cases {
+      let mut hsmp = HashMap::new();
+      let mut cnfg = ImgprssrConfig::default();
+      hsmp.insert("default_oversize_handling".to_owned(), handling_string.to_owned());
+      cnfg.default_oversize_handling = handling;
+      assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+    }
+  }
+
+  #[test]
+  fn invalid_oversize_handling_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("default_oversize_handling".to_owned(), "shrink".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["default_oversize_handling::shrink".to_owned()])
+    ))
+  }
+
   #[test]
   fn valid_file_source_parsed() {
     let mut hsmp = HashMap::new();
@@ -134,4 +472,369 @@ mod tests {
     cnfg.image_source = ImgSource::Http((client, "http://example.com".to_owned()));
     assert_eq!(from_hashmap(hsmp), Ok(cnfg))
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn valid_negotiate_format_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("negotiate_format".to_owned(), "true".to_owned());
+    cnfg.negotiate_format = true;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn valid_client_hints_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("client_hints".to_owned(), "true".to_owned());
+    cnfg.client_hints = true;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn valid_auto_orient_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("auto_orient".to_owned(), "false".to_owned());
+    cnfg.auto_orient = false;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_negotiate_format_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("negotiate_format".to_owned(), "sometimes".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["negotiate_format::sometimes".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn valid_encoder_defaults_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("default_quality".to_owned(), "70".to_owned());
+    hsmp.insert("default_lossless".to_owned(), "false".to_owned());
+    hsmp.insert("default_png_compression".to_owned(), "best".to_owned());
+    hsmp.insert("default_png_filter".to_owned(), "none".to_owned());
+    cnfg.default_quality = Some(70);
+    cnfg.default_lossless = false;
+    cnfg.default_png_compression = image::codecs::png::CompressionType::Best;
+    cnfg.default_png_filter = image::codecs::png::FilterType::NoFilter;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_quality_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("default_quality".to_owned(), "101".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["default_quality::101".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn valid_max_dpr_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("max_dpr".to_owned(), "2.5".to_owned());
+    cnfg.max_dpr = 2.5;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_max_dpr_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("max_dpr".to_owned(), "0".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["max_dpr::0".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn valid_watermark_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("watermark_image".to_owned(), "/logo.png".to_owned());
+    hsmp.insert("watermark_path_prefixes".to_owned(), "/products/, /gallery/".to_owned());
+    hsmp.insert("watermark_position".to_owned(), "northwest".to_owned());
+    hsmp.insert("watermark_margin".to_owned(), "0".to_owned());
+    hsmp.insert("watermark_opacity".to_owned(), "0.8".to_owned());
+    hsmp.insert("watermark_scale".to_owned(), "0.25".to_owned());
+    hsmp.insert("watermark_min_size".to_owned(), "400".to_owned());
+    cnfg.watermark_image = Some("/logo.png".to_owned());
+    cnfg.watermark_path_prefixes = vec!["/products/".to_owned(), "/gallery/".to_owned()];
+    cnfg.watermark_position = Gravity::NorthWest;
+    cnfg.watermark_margin = 0;
+    cnfg.watermark_opacity = 0.8;
+    cnfg.watermark_scale = 0.25;
+    cnfg.watermark_min_size = 400;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_watermark_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("watermark_image".to_owned(), "logo.png".to_owned());
+    hsmp.insert("watermark_position".to_owned(), "smart".to_owned());
+    hsmp.insert("watermark_opacity".to_owned(), "1.5".to_owned());
+    hsmp.insert("watermark_scale".to_owned(), "0".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec![
+        "watermark_image::logo.png".to_owned(),
+        "watermark_position::smart".to_owned(),
+        "watermark_opacity::1.5".to_owned(),
+        "watermark_scale::0".to_owned()
+      ])
+    ))
+  }
+
+  #[test]
+  fn valid_presets_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("preset_thumb".to_owned(), "width=150&height=150&filter=lanczos3".to_owned());
+    hsmp.insert("preset_hero".to_owned(), "width=1600&format=webp".to_owned());
+    cnfg.presets.insert("thumb".to_owned(), "width=150&height=150&filter=lanczos3".to_owned());
+    cnfg.presets.insert("hero".to_owned(), "width=1600&format=webp".to_owned());
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_presets_all_returned() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("preset_thumb".to_owned(), "width=150&height=150".to_owned());
+    hsmp.insert("preset_card".to_owned(), "width=wide".to_owned());
+    hsmp.insert("preset_hero".to_owned(), "format=jxl".to_owned());
+    hsmp.insert("preset_".to_owned(), "width=10".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec![
+        "preset_::width=10".to_owned(),
+        "preset_card::width=wide".to_owned(),
+        "preset_hero::format=jxl".to_owned()
+      ])
+    ))
+  }
+
+  #[test]
+  fn signing_key_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("signing_key".to_owned(), "s3cret".to_owned());
+    cnfg.signing_key = Some("s3cret".to_owned());
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn client_hints_refused_with_signing_key() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("signing_key".to_owned(), "s3cret".to_owned());
+    hsmp.insert("client_hints".to_owned(), "true".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["client_hints::true".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn empty_signing_key_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("signing_key".to_owned(), "".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["signing_key::".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn allowed_sizes_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("allowed_widths".to_owned(), "800, 200,400,200".to_owned());
+    hsmp.insert("allowed_heights".to_owned(), "150".to_owned());
+    hsmp.insert("size_bucketing".to_owned(), "true".to_owned());
+    cnfg.allowed_widths = vec![200, 400, 800];
+    cnfg.allowed_heights = vec![150];
+    cnfg.size_bucketing = true;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_allowed_sizes_return_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("allowed_widths".to_owned(), "200,wide".to_owned());
+    hsmp.insert("allowed_heights".to_owned(), "0,100".to_owned());
+    hsmp.insert("size_bucketing".to_owned(), "sometimes".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec![
+        "allowed_widths::200,wide".to_owned(),
+        "allowed_heights::0,100".to_owned(),
+        "size_bucketing::sometimes".to_owned()
+      ])
+    ))
+  }
+
+  #[test]
+  fn presets_must_use_allowed_sizes() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("allowed_widths".to_owned(), "150".to_owned());
+    hsmp.insert("preset_thumb".to_owned(), "width=150".to_owned());
+    hsmp.insert("preset_hero".to_owned(), "width=1600".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["preset_hero::width=1600".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn size_limits_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("max_source_width".to_owned(), "4000".to_owned());
+    hsmp.insert("max_source_height".to_owned(), "3000".to_owned());
+    hsmp.insert("max_source_pixels".to_owned(), "12000000".to_owned());
+    hsmp.insert("max_source_bytes".to_owned(), "1048576".to_owned());
+    hsmp.insert("max_output_width".to_owned(), "2000".to_owned());
+    hsmp.insert("max_output_height".to_owned(), "1500".to_owned());
+    cnfg.max_source_width = 4000;
+    cnfg.max_source_height = 3000;
+    cnfg.max_source_pixels = 12_000_000;
+    cnfg.max_source_bytes = 1_048_576;
+    cnfg.max_output_width = 2000;
+    cnfg.max_output_height = 1500;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_size_limits_return_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("max_source_width".to_owned(), "0".to_owned());
+    hsmp.insert("max_output_height".to_owned(), "tall".to_owned());
+    hsmp.insert("max_source_pixels".to_owned(), "-1".to_owned());
+    hsmp.insert("max_source_bytes".to_owned(), "0".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec![
+        "max_source_width::0".to_owned(),
+        "max_output_height::tall".to_owned(),
+        "max_source_pixels::-1".to_owned(),
+        "max_source_bytes::0".to_owned()
+      ])
+    ))
+  }
+
+  #[test]
+  fn symlink_policy_parsed() {
+    let cases = [
+      (SymlinkPolicy::Never, "never"),
+      (SymlinkPolicy::WithinRoot, "within_root"),
+      (SymlinkPolicy::Always, "always"),
+    ];
+    for (policy, string) in cases {
+      let mut hsmp = HashMap::new();
+      let mut cnfg = ImgprssrConfig::default();
+      hsmp.insert("follow_symlinks".to_owned(), string.to_owned());
+      cnfg.follow_symlinks = policy;
+      assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+    }
+  }
+
+  #[test]
+  fn invalid_symlink_policy_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("follow_symlinks".to_owned(), "sometimes".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["follow_symlinks::sometimes".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn upstream_timeout_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("upstream_timeout".to_owned(), "5".to_owned());
+    cnfg.upstream_timeout = 5;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_upstream_timeout_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("upstream_timeout".to_owned(), "0".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["upstream_timeout::0".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn max_redirects_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("max_redirects".to_owned(), "0".to_owned());
+    cnfg.max_redirects = 0;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn cross_host_redirects_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("cross_host_redirects".to_owned(), "true".to_owned());
+    cnfg.cross_host_redirects = true;
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_max_redirects_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("max_redirects".to_owned(), "lots".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["max_redirects::lots".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn cache_control_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("cache_control".to_owned(), "public, max-age=31536000, immutable".to_owned());
+    hsmp.insert("error_cache_control".to_owned(), "".to_owned());
+    cnfg.cache_control = "public, max-age=31536000, immutable".to_owned();
+    cnfg.error_cache_control = "".to_owned();
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_cache_control_returns_err() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("cache_control".to_owned(), "max-age=60\nx-injected: 1".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec!["cache_control::max-age=60\nx-injected: 1".to_owned()])
+    ))
+  }
+
+  #[test]
+  fn extra_headers_parsed() {
+    let mut hsmp = HashMap::new();
+    let mut cnfg = ImgprssrConfig::default();
+    hsmp.insert("header_x_content_type_options".to_owned(), "nosniff".to_owned());
+    hsmp.insert("header_access_control_allow_origin".to_owned(), "*".to_owned());
+    cnfg.extra_headers = vec![
+      (HeaderName::from_static("access-control-allow-origin"), HeaderValue::from_static("*")),
+      (HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff"))
+    ];
+    assert_eq!(from_hashmap(hsmp), Ok(cnfg))
+  }
+
+  #[test]
+  fn invalid_extra_headers_all_returned() {
+    let mut hsmp = HashMap::new();
+    hsmp.insert("header_x_fine".to_owned(), "yes".to_owned());
+    hsmp.insert("header_".to_owned(), "nameless".to_owned());
+    hsmp.insert("header_x_bad_value".to_owned(), "line\nbreak".to_owned());
+    hsmp.insert("header_x bad name".to_owned(), "spaced".to_owned());
+    assert_eq!(from_hashmap(hsmp), Err(
+      ImgprssrConfigErr::InvalidValues(vec![
+        "header_::nameless".to_owned(),
+        "header_x bad name::spaced".to_owned(),
+        "header_x_bad_value::line\nbreak".to_owned()
+      ])
+    ))
+  }
+}
@@ -0,0 +1,56 @@
+use crate::{parameters::ImageParameterParseError, signing::SignatureError};
+
+// Everything that can stop a request producing an image, by the stage it
+// happens in
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum ImgprssrError {
+  // Request
+  InvalidSignature(SignatureError),
+  UnknownPreset,
+  InvalidParameters(Vec<ImageParameterParseError>),
+  // Source
+  NotFound,
+  UpstreamFailed,
+  UpstreamTimedOut,
+  WatermarkNotFound,
+  WatermarkUnavailable,
+  // Decode
+  UnsupportedFormat,
+  SourceTooLarge,
+  Decode(String),
+  // Process
+  SourceTooSmall(u32, u32),
+  CropOutOfBounds(u32, u32),
+  OutputTooLarge,
+  OutputSizeNotAllowed(u32, u32),
+  // Encode
+  Encode(String)
+}
+
+// Decoding only ever sees bytes already in memory, so anything
+// besides the format and limits is a broken image
+impl From<image::ImageError> for ImgprssrError {
+  fn from(err: image::ImageError) -> Self {
+    match err {
+      image::ImageError::Limits(_) => ImgprssrError::SourceTooLarge,
+      image::ImageError::Unsupported(_) => ImgprssrError::UnsupportedFormat,
+      err => ImgprssrError::Decode(err.to_string()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn image_errors_map_to_decode_stage() {
+    let limits = image::ImageError::Limits(image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError));
+    assert_eq!(ImgprssrError::from(limits), ImgprssrError::SourceTooLarge);
+    let unsupported = image::guess_format(b"not an image").unwrap_err();
+    assert_eq!(ImgprssrError::from(unsupported), ImgprssrError::UnsupportedFormat);
+    let truncated = image::load_from_memory_with_format(b"\x89PNG\r\n\x1a\n", image::ImageFormat::Png).unwrap_err();
+    assert!(matches!(ImgprssrError::from(truncated), ImgprssrError::Decode(_)));
+  }
+}
@@ -1,3 +1,5 @@
 pub mod appconfig;
 pub mod parameters;
-pub mod process;
\ No newline at end of file
+pub mod process;
+pub mod signing;
+pub mod error;
\ No newline at end of file
@@ -3,26 +3,144 @@ use std::convert::Infallible;
 use std::net::SocketAddr;
 
 use config::Config;
-use imgprssr::{appconfig, process};
+use imgprssr::{appconfig, process, error::ImgprssrError, parameters::ImageParameterParseError, signing::SignatureError};
+use serde_json::json;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
 
 use futures::stream::StreamExt;
 
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{Body, Request, Response, Server, StatusCode, header};
 use hyper::service::{make_service_fn, service_fn};
 mod source;
 
 async fn handle_image_request(settings: appconfig::ImgprssrConfig, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    let sourced = source::get_source_image(&settings, req).await;
-    match sourced {
-        Ok((img, img_format, params)) => Ok(Response::builder()
-            .status(StatusCode::OK)
-            .body(process::process_image_to_buffer(&settings, img, img_format, params).into()).unwrap()),
-        Err(err_res) => Ok(err_res),
+    let sourced = match source::get_source_image(&settings, req).await {
+        Ok(sourced) => sourced,
+        Err(err) => return Ok(error_response(&settings, err)),
+    };
+    let source::SourcedImage { image, format, params, vary, watermark, cache_headers } = sourced;
+    let output_format = params.format.unwrap_or(format);
+    let body = match process::process_image_to_buffer(&settings, image, output_format, params, watermark) {
+        Ok(body) => body,
+        Err(err) => return Ok(error_response(&settings, err)),
+    };
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, output_format.to_mime_type())
+        .header(header::CONTENT_LENGTH, body.len());
+    if !vary.is_empty() {
+        response = response.header(header::VARY, vary.join(", "));
     }
+    if settings.client_hints && settings.signing_key.is_none() {
+        response = response.header("Accept-CH", source::CLIENT_HINTS);
+    }
+    for (name, val) in [(header::CACHE_CONTROL, cache_headers.cache_control), (header::LAST_MODIFIED, cache_headers.last_modified), (header::ETAG, cache_headers.etag)] {
+        if let Some(val) = val {
+            response = response.header(name, val);
+        }
+    }
+    Ok(with_standard_headers(&settings, response.body(body.into()).unwrap(), &settings.cache_control))
+}
+
+// The configured Cache-Control, unless it's empty or the response already
+// has one, then the operator's own headers over the top of everything
+fn with_standard_headers(settings: &appconfig::ImgprssrConfig, mut response: Response<Body>, cache_control: &str) -> Response<Body> {
+    let headers = response.headers_mut();
+    if !cache_control.is_empty() && !headers.contains_key(header::CACHE_CONTROL) {
+        if let Ok(val) = header::HeaderValue::from_str(cache_control) {
+            headers.insert(header::CACHE_CONTROL, val);
+        }
+    }
+    for (name, val) in &settings.extra_headers {
+        headers.insert(name.clone(), val.clone());
+    }
+    response
+}
+
+// An RFC 7807 problem details response
+fn problem_response(status: StatusCode, detail: &str, invalid_params: Vec<serde_json::Value>) -> Response<Body> {
+    let mut problem = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": detail
+    });
+    if !invalid_params.is_empty() {
+        problem["invalid-params"] = invalid_params.into();
+    }
+    let body = problem.to_string();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/problem+json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body.into()).unwrap()
+}
+
+fn one_of<T: ToString>(values: impl Iterator<Item = T>) -> String {
+    format!("one of {}", values.map(|value| value.to_string()).collect::<Vec<_>>().join(", "))
 }
 
+fn invalid_params(settings: &appconfig::ImgprssrConfig, errors: &[ImageParameterParseError]) -> Vec<serde_json::Value> {
+    errors.iter().map(|err| {
+        let (reason, accepted) = match err {
+            ImageParameterParseError::WidthNotAllowedError => ("not an allowed size", one_of(settings.allowed_widths.iter())),
+            ImageParameterParseError::HeightNotAllowedError => ("not an allowed size", one_of(settings.allowed_heights.iter())),
+            _ => ("invalid value", err.accepted().to_owned()),
+        };
+        json!({ "name": err.parameter(), "reason": reason, "accepted": accepted })
+    }).collect()
+}
+
+fn error_response(settings: &appconfig::ImgprssrConfig, err: ImgprssrError) -> Response<Body> {
+    let response = match err {
+        ImgprssrError::InvalidSignature(SignatureError::MissingSignature) => problem_response(StatusCode::FORBIDDEN, "Request must be signed", vec![]),
+        ImgprssrError::InvalidSignature(SignatureError::Expired) => problem_response(StatusCode::FORBIDDEN, "Signed URL has expired", vec![]),
+        ImgprssrError::InvalidSignature(_) => problem_response(StatusCode::FORBIDDEN, "Signature does not match the request", vec![]),
+        ImgprssrError::UnknownPreset => {
+            let mut names: Vec<&String> = settings.presets.keys().collect();
+            names.sort_unstable();
+            problem_response(StatusCode::BAD_REQUEST, "Unknown preset", vec![
+                json!({ "name": "preset", "reason": "not a configured preset", "accepted": one_of(names.into_iter()) })
+            ])
+        },
+        ImgprssrError::InvalidParameters(errors) => problem_response(StatusCode::BAD_REQUEST, "One or more query parameters are invalid", invalid_params(settings, &errors)),
+        ImgprssrError::NotFound => problem_response(StatusCode::NOT_FOUND, "Source image not found", vec![]),
+        ImgprssrError::UpstreamFailed => problem_response(StatusCode::BAD_GATEWAY, "Image source failed to provide the image", vec![]),
+        ImgprssrError::UpstreamTimedOut => problem_response(StatusCode::GATEWAY_TIMEOUT, "Image source took too long to respond", vec![]),
+        ImgprssrError::WatermarkNotFound => problem_response(StatusCode::NOT_FOUND, "Watermark not found", vec![]),
+        // A forced watermark going missing is our problem, not the client's
+        ImgprssrError::WatermarkUnavailable => problem_response(StatusCode::INTERNAL_SERVER_ERROR, "Configured watermark could not be loaded", vec![]),
+        ImgprssrError::UnsupportedFormat => problem_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Source image is not in a supported format", vec![]),
+        ImgprssrError::SourceTooLarge => problem_response(StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Source image is larger than the maximum of {}x{}, {} pixels or {} bytes", settings.max_source_width, settings.max_source_height, settings.max_source_pixels, settings.max_source_bytes), vec![]),
+        ImgprssrError::Decode(_) => problem_response(StatusCode::UNPROCESSABLE_ENTITY, "Source image could not be decoded", vec![]),
+        ImgprssrError::SourceTooSmall(width, height) => problem_response(StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Source image is {}x{}, smaller than the requested size", width, height), vec![]),
+        ImgprssrError::CropOutOfBounds(width, height) => problem_response(StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Crop region falls outside the {}x{} source image", width, height), vec![]),
+        ImgprssrError::OutputTooLarge => problem_response(StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Requested output is larger than the maximum of {}x{}", settings.max_output_width, settings.max_output_height), vec![]),
+        ImgprssrError::OutputSizeNotAllowed(width, height) => problem_response(StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Requested output would be {}x{}, which isn't an allowed size", width, height), vec![]),
+        ImgprssrError::Encode(_) => problem_response(StatusCode::INTERNAL_SERVER_ERROR, "Image could not be encoded", vec![]),
+    };
+    with_standard_headers(settings, response, &settings.error_cache_control)
+}
+
+
+fn parse_address(config_address: &str) -> Option<[u8; 4]> {
+    let mut address = [0, 0, 0, 0];
+    let split = config_address.split('.');
+    let parsed = split.map(|a| a.parse::<u8>());
+    if parsed.clone().count() != 4 || !parsed.clone().all(|res| res.is_ok()) {
+        return None;
+    }
+    for (i, part) in parsed.enumerate() {
+        address[i] = part.unwrap();
+    }
+    Some(address)
+}
 
 pub fn generate_app_config() -> Result<(([u8; 4], u16), appconfig::ImgprssrConfig), appconfig::ImgprssrConfigErr> {
     let raw_hashmap = Config::builder()
@@ -37,15 +155,9 @@ pub fn generate_app_config() -> Result<(([u8; 4], u16), appconfig::ImgprssrConfi
     let mut port = 3000;
 
     if let Some(config_address) = raw_hashmap.get("address") {
-        let split = config_address.split(".");
-        let mut parsed = split.map(|a| a.parse::<u8>()).into_iter();
-        if parsed.clone().count() != 4 && !parsed.clone().all(|res| res.is_ok()){
-            return Err(appconfig::ImgprssrConfigErr::InvalidValues(vec!["address".to_owned()]))
-        }
-        let mut i = 0;
-        while let Some(part) = parsed.next() {
-            address[i] = part.unwrap();
-            i += 1;
+        match parse_address(config_address) {
+            Some(parsed) => address = parsed,
+            None => return Err(appconfig::ImgprssrConfigErr::InvalidValues(vec!["address".to_owned()]))
         }
     }
 
@@ -67,7 +179,7 @@ pub fn generate_app_config() -> Result<(([u8; 4], u16), appconfig::ImgprssrConfi
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let mut signals = Signals::new(&[
+    let mut signals = Signals::new([
         SIGTERM,
         SIGINT,
         SIGQUIT,
@@ -75,7 +187,7 @@ async fn main() -> Result<(), std::io::Error> {
 
     let ((bind, port), settings) = generate_app_config().unwrap();
     
-    let addr = SocketAddr::from((bind.clone(), port.clone()));
+    let addr = SocketAddr::from((bind, port));
 
     println!("imgprssr sourcing images from {}", match &settings.image_source {
         appconfig::ImgSource::Folder(fldr) => fldr,
@@ -95,11 +207,9 @@ async fn main() -> Result<(), std::io::Error> {
     let server = Server::bind(&addr)
         .serve(make_svc)
         .with_graceful_shutdown(async {
-            while let Some(signal) = signals.next().await {
+            if let Some(signal) = signals.next().await {
                 match signal {
-                    SIGTERM | SIGINT | SIGQUIT => {
-                        return ();
-                    },
+                    SIGTERM | SIGINT | SIGQUIT => {},
                     _ => unreachable!(),
                 }
             }
@@ -111,4 +221,154 @@ async fn main() -> Result<(), std::io::Error> {
         eprintln!("server error: {}", e);
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imgprssr::appconfig::ImgprssrConfig;
+
+    #[test]
+    fn parses_only_four_part_addresses() {
+        let cases = [
+            ("0.0.0.0", Some([0, 0, 0, 0])),
+            ("127.0.0.1", Some([127, 0, 0, 1])),
+            ("1.2.3.4.5", None),
+            ("1.2.3", None),
+            ("1.2.3.256", None),
+            ("localhost", None)
+        ];
+        for (config_address, expected) in cases {
+            assert_eq!(parse_address(config_address), expected, "{config_address}");
+        }
+    }
+
+    async fn respond(settings: &ImgprssrConfig, uri: &str) -> (StatusCode, Option<String>, Vec<u8>) {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let res = handle_image_request(settings.clone(), req).await.unwrap();
+        let status = res.status();
+        let content_type = res.headers().get(header::CONTENT_TYPE).map(|val| val.to_str().unwrap().to_owned());
+        (status, content_type, hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec())
+    }
+
+    #[tokio::test]
+    async fn responds_with_the_processed_image() {
+        let (status, content_type, body) = respond(&ImgprssrConfig::default(), "/test_card_sml.png?width=100").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("image/png"));
+        assert_eq!(image::load_from_memory(&body).unwrap().width(), 100);
+    }
+
+    #[tokio::test]
+    async fn lists_every_invalid_parameter() {
+        let settings = ImgprssrConfig { allowed_heights: vec![100, 200], ..Default::default() };
+        let (status, content_type, body) = respond(&settings, "/test_card_sml.png?width=wide&height=150&format=jxl").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["title"], "Bad Request");
+        assert_eq!(problem["invalid-params"], json!([
+            { "name": "width", "reason": "invalid value", "accepted": "a whole number of pixels" },
+            { "name": "format", "reason": "invalid value", "accepted": "one of png, jpeg, webp, gif, bmp, tiff, ico, tga" },
+            { "name": "height", "reason": "not an allowed size", "accepted": "one of 100, 200" }
+        ]));
+    }
+
+    #[tokio::test]
+    async fn broken_sources_respond_instead_of_panicking() {
+        let root = std::env::temp_dir().join(format!("imgprssr-handler-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let png = std::fs::read("./images/test_card_sml.png").unwrap();
+        std::fs::write(root.join("truncated.png"), &png[..png.len() / 2]).unwrap();
+        std::fs::write(root.join("mislabelled.jpg"), &png).unwrap();
+        std::fs::write(root.join("notes.txt"), "not an image").unwrap();
+        let settings = ImgprssrConfig { image_source: appconfig::ImgSource::Folder(root.to_str().unwrap().to_owned()), ..Default::default() };
+        let cases = [
+            ("/missing.png", StatusCode::NOT_FOUND),
+            ("/notes.txt", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            ("/truncated.png", StatusCode::UNPROCESSABLE_ENTITY),
+            ("/mislabelled.jpg", StatusCode::UNPROCESSABLE_ENTITY)
+        ];
+        for (uri, expected) in cases {
+            let (status, content_type, body) = respond(&settings, uri).await;
+            assert_eq!(status, expected, "{uri}");
+            assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+            let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(problem["status"], expected.as_u16());
+        }
+    }
+
+    #[tokio::test]
+    async fn varies_on_client_hints_even_when_none_are_sent() {
+        let settings = ImgprssrConfig { client_hints: true, ..Default::default() };
+        let vary = |uri: &'static str| {
+            let settings = settings.clone();
+            async move {
+                let res = handle_image_request(settings, Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+                assert_eq!(res.status(), StatusCode::OK, "{uri}");
+                res.headers().get(header::VARY).map(|val| val.to_str().unwrap().to_owned())
+            }
+        };
+        assert_eq!(vary("/test_card_sml.png").await.as_deref(), Some("Sec-CH-DPR, Sec-CH-Width, Sec-CH-Viewport-Width"));
+        assert_eq!(vary("/test_card_sml.png?width=100").await.as_deref(), Some("Sec-CH-DPR"));
+        assert_eq!(vary("/test_card_sml.png?width=100&dpr=2").await, None);
+    }
+
+    #[tokio::test]
+    async fn sets_standard_headers() {
+        let settings = ImgprssrConfig {
+            extra_headers: vec![(header::HeaderName::from_static("x-content-type-options"), header::HeaderValue::from_static("nosniff"))],
+            ..Default::default()
+        };
+        let value = |res: &Response<Body>, name: &str| res.headers().get(name).map(|val| val.to_str().unwrap().to_owned());
+
+        let res = handle_image_request(settings.clone(), Request::get("/test_card_sml.png?width=100&format=jpeg").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(value(&res, "content-type").as_deref(), Some("image/jpeg"));
+        assert_eq!(value(&res, "cache-control").as_deref(), Some("public, max-age=86400"));
+        assert_eq!(value(&res, "x-content-type-options").as_deref(), Some("nosniff"));
+        assert!(httpdate::parse_http_date(&value(&res, "last-modified").unwrap()).is_ok());
+        let length = value(&res, "content-length").unwrap();
+        assert_eq!(length.parse::<usize>().unwrap(), hyper::body::to_bytes(res.into_body()).await.unwrap().len());
+
+        let res = handle_image_request(settings.clone(), Request::get("/missing.png").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(value(&res, "cache-control").as_deref(), Some("no-store"));
+        assert_eq!(value(&res, "x-content-type-options").as_deref(), Some("nosniff"));
+        let length = value(&res, "content-length").unwrap();
+        assert_eq!(length.parse::<usize>().unwrap(), hyper::body::to_bytes(res.into_body()).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn empty_cache_control_is_left_out() {
+        let settings = ImgprssrConfig { cache_control: "".to_owned(), error_cache_control: "".to_owned(), ..Default::default() };
+        for uri in ["/test_card_sml.png", "/missing.png"] {
+            let res = handle_image_request(settings.clone(), Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+            assert!(!res.headers().contains_key(header::CACHE_CONTROL), "{uri}");
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let cases = [
+            (ImgprssrError::InvalidSignature(SignatureError::Expired), StatusCode::FORBIDDEN),
+            (ImgprssrError::UnknownPreset, StatusCode::BAD_REQUEST),
+            (ImgprssrError::InvalidParameters(vec![ImageParameterParseError::WidthParseError]), StatusCode::BAD_REQUEST),
+            (ImgprssrError::NotFound, StatusCode::NOT_FOUND),
+            (ImgprssrError::UpstreamFailed, StatusCode::BAD_GATEWAY),
+            (ImgprssrError::UpstreamTimedOut, StatusCode::GATEWAY_TIMEOUT),
+            (ImgprssrError::WatermarkNotFound, StatusCode::NOT_FOUND),
+            (ImgprssrError::WatermarkUnavailable, StatusCode::INTERNAL_SERVER_ERROR),
+            (ImgprssrError::UnsupportedFormat, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (ImgprssrError::SourceTooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+            (ImgprssrError::Decode("truncated".to_owned()), StatusCode::UNPROCESSABLE_ENTITY),
+            (ImgprssrError::SourceTooSmall(10, 10), StatusCode::UNPROCESSABLE_ENTITY),
+            (ImgprssrError::CropOutOfBounds(10, 10), StatusCode::UNPROCESSABLE_ENTITY),
+            (ImgprssrError::OutputTooLarge, StatusCode::UNPROCESSABLE_ENTITY),
+            (ImgprssrError::OutputSizeNotAllowed(10, 10), StatusCode::UNPROCESSABLE_ENTITY),
+            (ImgprssrError::Encode("too big".to_owned()), StatusCode::INTERNAL_SERVER_ERROR)
+        ];
+        for (err, status) in cases {
+            assert_eq!(error_response(&ImgprssrConfig::default(), err).status(), status);
+        }
+    }
+}
@@ -1,12 +1,112 @@
 use std::{str::FromStr, collections::HashMap};
 
+use crate::appconfig::ImgprssrConfig;
+
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
 pub enum ImageParameterParseError {
   WidthParseError,
   HeightParseError,
   FilterParseError,
-  OversizeParseError
+  OversizeParseError,
+  FormatParseError,
+  QualityParseError,
+  LosslessParseError,
+  CompressionParseError,
+  PngFilterParseError,
+  FitParseError,
+  GravityParseError,
+  FocalPointParseError,
+  CropParseError,
+  DprParseError,
+  RotateParseError,
+  FlipParseError,
+  BlurParseError,
+  SharpenParseError,
+  BrightnessParseError,
+  ContrastParseError,
+  HueParseError,
+  SaturationParseError,
+  GrayscaleParseError,
+  InvertParseError,
+  BackgroundParseError,
+  PadParseError,
+  WatermarkParseError,
+  TrimParseError,
+  WidthNotAllowedError,
+  HeightNotAllowedError
+}
+
+impl ImageParameterParseError {
+  // The query parameter at fault
+  pub fn parameter(&self) -> &'static str {
+    match self {
+      ImageParameterParseError::WidthParseError | ImageParameterParseError::WidthNotAllowedError => "width",
+      ImageParameterParseError::HeightParseError | ImageParameterParseError::HeightNotAllowedError => "height",
+      ImageParameterParseError::FilterParseError => "filter",
+      ImageParameterParseError::OversizeParseError => "oversizehandling",
+      ImageParameterParseError::FormatParseError => "format",
+      ImageParameterParseError::QualityParseError => "quality",
+      ImageParameterParseError::LosslessParseError => "lossless",
+      ImageParameterParseError::CompressionParseError => "compression",
+      ImageParameterParseError::PngFilterParseError => "pngfilter",
+      ImageParameterParseError::FitParseError => "fit",
+      ImageParameterParseError::GravityParseError => "gravity",
+      ImageParameterParseError::FocalPointParseError => "fp-x, fp-y",
+      ImageParameterParseError::CropParseError => "crop",
+      ImageParameterParseError::DprParseError => "dpr",
+      ImageParameterParseError::RotateParseError => "rotate",
+      ImageParameterParseError::FlipParseError => "flip",
+      ImageParameterParseError::BlurParseError => "blur",
+      ImageParameterParseError::SharpenParseError => "sharpen",
+      ImageParameterParseError::BrightnessParseError => "brightness",
+      ImageParameterParseError::ContrastParseError => "contrast",
+      ImageParameterParseError::HueParseError => "hue",
+      ImageParameterParseError::SaturationParseError => "saturation",
+      ImageParameterParseError::GrayscaleParseError => "grayscale",
+      ImageParameterParseError::InvertParseError => "invert",
+      ImageParameterParseError::BackgroundParseError => "bg",
+      ImageParameterParseError::PadParseError => "pad",
+      ImageParameterParseError::WatermarkParseError => "wm",
+      ImageParameterParseError::TrimParseError => "trim",
+    }
+  }
+
+  // What the parameter takes, for telling the client how to fix it
+  pub fn accepted(&self) -> &'static str {
+    match self {
+      ImageParameterParseError::WidthParseError | ImageParameterParseError::HeightParseError => "a whole number of pixels",
+      ImageParameterParseError::WidthNotAllowedError | ImageParameterParseError::HeightNotAllowedError => "one of the configured sizes",
+      ImageParameterParseError::FilterParseError => "one of nearest, triangle, catmullrom, gaussian, lanczos3",
+      ImageParameterParseError::OversizeParseError => "one of clamp, upscale, reject",
+      ImageParameterParseError::FormatParseError => "one of png, jpeg, webp, gif, bmp, tiff, ico, tga",
+      ImageParameterParseError::QualityParseError => "a whole number from 1 to 100",
+      ImageParameterParseError::LosslessParseError
+        | ImageParameterParseError::GrayscaleParseError
+        | ImageParameterParseError::InvertParseError => "true or false",
+      ImageParameterParseError::CompressionParseError => "one of fast, default, best",
+      ImageParameterParseError::PngFilterParseError => "one of none, sub, up, avg, paeth, adaptive",
+      ImageParameterParseError::FitParseError => "one of cover, contain, fill, inside, outside",
+      ImageParameterParseError::GravityParseError => "one of centre, north, northeast, east, southeast, south, southwest, west, northwest, smart",
+      ImageParameterParseError::FocalPointParseError => "a fraction from 0 to 1",
+      ImageParameterParseError::CropParseError => "x,y,width,height in pixels, or as fractions from 0 to 1",
+      ImageParameterParseError::DprParseError => "a number greater than 0 and up to 10",
+      ImageParameterParseError::RotateParseError => "one of 90, 180, 270",
+      ImageParameterParseError::FlipParseError => "one of h, v, hv",
+      ImageParameterParseError::BlurParseError => "a sigma greater than 0 and up to 20",
+      ImageParameterParseError::SharpenParseError => "a sigma greater than 0 and up to 10, optionally followed by ,threshold",
+      ImageParameterParseError::BrightnessParseError => "a whole number from -255 to 255",
+      ImageParameterParseError::ContrastParseError => "a number from -100 to 100",
+      ImageParameterParseError::HueParseError => "a whole number of degrees from -360 to 360",
+      ImageParameterParseError::SaturationParseError => "a number from 0 to 3",
+      ImageParameterParseError::BackgroundParseError => "a hex colour, rrggbb or rrggbbaa",
+      ImageParameterParseError::PadParseError => "pixels for every edge, or top,right,bottom,left",
+      ImageParameterParseError::WatermarkParseError => "an image path starting with /",
+      ImageParameterParseError::TrimParseError => "true, false, or a colour tolerance from 0 to 255",
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -14,7 +114,9 @@ pub enum ImageParameterParseError {
 #[derive(Clone)]
 #[derive(Copy)]
 pub enum OversizedImageHandling {
-  Clamp
+  Clamp,
+  Upscale,
+  Reject
 }
 
 impl FromStr for OversizedImageHandling {
@@ -23,6 +125,171 @@ impl FromStr for OversizedImageHandling {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "clamp" => Ok(OversizedImageHandling::Clamp),
+            "upscale" => Ok(OversizedImageHandling::Upscale),
+            "reject" => Ok(OversizedImageHandling::Reject),
+            _ => Err(std::fmt::Error)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum FitMode {
+  Cover,
+  Contain,
+  Fill,
+  Inside,
+  Outside
+}
+
+impl FromStr for FitMode {
+    type Err = std::fmt::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cover" => Ok(FitMode::Cover),
+            "contain" => Ok(FitMode::Contain),
+            "fill" => Ok(FitMode::Fill),
+            "inside" => Ok(FitMode::Inside),
+            "outside" => Ok(FitMode::Outside),
+            _ => Err(std::fmt::Error)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum Gravity {
+  Centre,
+  North,
+  NorthEast,
+  East,
+  SouthEast,
+  South,
+  SouthWest,
+  West,
+  NorthWest,
+  Smart
+}
+
+impl Gravity {
+  // Where the gravity sits as fractions of the image width and height -
+  // smart gravity depends on the image, so falls back to the centre here
+  pub fn focal_point(&self) -> (f32, f32) {
+    match self {
+      Gravity::Centre | Gravity::Smart => (0.5, 0.5),
+      Gravity::North => (0.5, 0.0),
+      Gravity::NorthEast => (1.0, 0.0),
+      Gravity::East => (1.0, 0.5),
+      Gravity::SouthEast => (1.0, 1.0),
+      Gravity::South => (0.5, 1.0),
+      Gravity::SouthWest => (0.0, 1.0),
+      Gravity::West => (0.0, 0.5),
+      Gravity::NorthWest => (0.0, 0.0),
+    }
+  }
+}
+
+impl FromStr for Gravity {
+    type Err = std::fmt::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "centre" | "center" => Ok(Gravity::Centre),
+            "north" => Ok(Gravity::North),
+            "northeast" => Ok(Gravity::NorthEast),
+            "east" => Ok(Gravity::East),
+            "southeast" => Ok(Gravity::SouthEast),
+            "south" => Ok(Gravity::South),
+            "southwest" => Ok(Gravity::SouthWest),
+            "west" => Ok(Gravity::West),
+            "northwest" => Ok(Gravity::NorthWest),
+            "smart" => Ok(Gravity::Smart),
+            _ => Err(std::fmt::Error)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum CropRegion {
+  Pixels(u32, u32, u32, u32),
+  Fractions(f32, f32, f32, f32)
+}
+
+impl FromStr for CropRegion {
+    type Err = std::fmt::Error;
+
+    // Either whole pixels ("10,20,300,200"), or fractions of the
+    // source when any value is a decimal ("0.1,0.2,0.5,0.5")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prts: Vec<&str> = s.split(',').collect();
+        if prts.len() != 4 {
+            return Err(std::fmt::Error);
+        }
+        if prts.iter().any(|p| p.contains('.')) {
+            let fractions: Vec<f32> = prts.iter()
+                .map(|p| p.parse::<f32>().ok().filter(|f| (0.0..=1.0).contains(f)))
+                .collect::<Option<Vec<f32>>>()
+                .ok_or(std::fmt::Error)?;
+            Ok(CropRegion::Fractions(fractions[0], fractions[1], fractions[2], fractions[3]))
+        } else {
+            let pixels: Vec<u32> = prts.iter()
+                .map(|p| p.parse::<u32>().ok())
+                .collect::<Option<Vec<u32>>>()
+                .ok_or(std::fmt::Error)?;
+            Ok(CropRegion::Pixels(pixels[0], pixels[1], pixels[2], pixels[3]))
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum Rotation {
+  Rotate90,
+  Rotate180,
+  Rotate270
+}
+
+impl FromStr for Rotation {
+    type Err = std::fmt::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "90" => Ok(Rotation::Rotate90),
+            "180" => Ok(Rotation::Rotate180),
+            "270" => Ok(Rotation::Rotate270),
+            _ => Err(std::fmt::Error)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum Flip {
+  Horizontal,
+  Vertical,
+  Both
+}
+
+impl FromStr for Flip {
+    type Err = std::fmt::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h" => Ok(Flip::Horizontal),
+            "v" => Ok(Flip::Vertical),
+            "hv" | "vh" => Ok(Flip::Both),
             _ => Err(std::fmt::Error)
         }
     }
@@ -30,11 +297,37 @@ impl FromStr for OversizedImageHandling {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[derive(Default)]
+#[derive(Clone)]
 pub struct ImageParameters {
   pub width: Option<u32>,
   pub height: Option<u32>,
   pub scaling_filter: Option<image::imageops::FilterType>,
-  pub oversized_handling: Option<OversizedImageHandling>
+  pub oversized_handling: Option<OversizedImageHandling>,
+  pub format: Option<image::ImageFormat>,
+  pub quality: Option<u8>,
+  pub lossless: Option<bool>,
+  pub png_compression: Option<image::codecs::png::CompressionType>,
+  pub png_filter: Option<image::codecs::png::FilterType>,
+  pub fit: Option<FitMode>,
+  pub gravity: Option<Gravity>,
+  pub focal_point: Option<(f32, f32)>,
+  pub crop: Option<CropRegion>,
+  pub dpr: Option<f32>,
+  pub rotate: Option<Rotation>,
+  pub flip: Option<Flip>,
+  pub blur: Option<f32>,
+  pub sharpen: Option<(f32, i32)>,
+  pub brightness: Option<i32>,
+  pub contrast: Option<f32>,
+  pub hue: Option<i32>,
+  pub saturation: Option<f32>,
+  pub grayscale: bool,
+  pub invert: bool,
+  pub background: Option<image::Rgba<u8>>,
+  pub pad: Option<(u32, u32, u32, u32)>,
+  pub watermark: Option<String>,
+  pub trim: Option<u8>
 }
 
 pub fn str_to_filter(filter_string: &str) -> Result<image::imageops::FilterType, ImageParameterParseError> {
@@ -48,22 +341,175 @@ pub fn str_to_filter(filter_string: &str) -> Result<image::imageops::FilterType,
 }
 }
 
+pub fn str_to_format(format_string: &str) -> Result<image::ImageFormat, ImageParameterParseError> {
+  match format_string {
+    "png" => Ok(image::ImageFormat::Png),
+    "jpeg" | "jpg" => Ok(image::ImageFormat::Jpeg),
+    "webp" => Ok(image::ImageFormat::WebP),
+    "gif" => Ok(image::ImageFormat::Gif),
+    "bmp" => Ok(image::ImageFormat::Bmp),
+    "tiff" => Ok(image::ImageFormat::Tiff),
+    "ico" => Ok(image::ImageFormat::Ico),
+    "tga" => Ok(image::ImageFormat::Tga),
+    _ => Err(ImageParameterParseError::FormatParseError)
+  }
+}
+
+pub fn str_to_quality(quality_string: &str) -> Result<u8, ImageParameterParseError> {
+  match quality_string.parse::<u8>() {
+    Ok(quality) if (1..=100).contains(&quality) => Ok(quality),
+    _ => Err(ImageParameterParseError::QualityParseError)
+  }
+}
+
+pub fn str_to_compression(compression_string: &str) -> Result<image::codecs::png::CompressionType, ImageParameterParseError> {
+  match compression_string {
+    "fast" => Ok(image::codecs::png::CompressionType::Fast),
+    "default" => Ok(image::codecs::png::CompressionType::Default),
+    "best" => Ok(image::codecs::png::CompressionType::Best),
+    _ => Err(ImageParameterParseError::CompressionParseError)
+  }
+}
+
+pub fn str_to_png_filter(filter_string: &str) -> Result<image::codecs::png::FilterType, ImageParameterParseError> {
+  match filter_string {
+    "none" => Ok(image::codecs::png::FilterType::NoFilter),
+    "sub" => Ok(image::codecs::png::FilterType::Sub),
+    "up" => Ok(image::codecs::png::FilterType::Up),
+    "avg" => Ok(image::codecs::png::FilterType::Avg),
+    "paeth" => Ok(image::codecs::png::FilterType::Paeth),
+    "adaptive" => Ok(image::codecs::png::FilterType::Adaptive),
+    _ => Err(ImageParameterParseError::PngFilterParseError)
+  }
+}
+
+fn str_to_fraction(fraction_string: &str) -> Option<f32> {
+  match fraction_string.parse::<f32>() {
+    Ok(fraction) if (0.0..=1.0).contains(&fraction) => Some(fraction),
+    _ => None
+  }
+}
+
+pub fn str_to_dpr(dpr_string: &str) -> Result<f32, ImageParameterParseError> {
+  match dpr_string.parse::<f32>() {
+    Ok(dpr) if dpr > 0.0 && dpr <= 10.0 => Ok(dpr),
+    _ => Err(ImageParameterParseError::DprParseError)
+  }
+}
+
+// Gaussian blurs get slower as sigma grows, so keep them to
+// something that stays quick on a large image
+pub const MAX_BLUR_SIGMA: f32 = 20.0;
+pub const MAX_SHARPEN_SIGMA: f32 = 10.0;
+
+fn str_to_sigma(sigma_string: &str, max: f32) -> Option<f32> {
+  match sigma_string.parse::<f32>() {
+    Ok(sigma) if sigma > 0.0 && sigma <= max => Some(sigma),
+    _ => None
+  }
+}
+
+// "sigma" or "sigma,threshold" with a threshold from 0 to 255
+fn str_to_sharpen(sharpen_string: &str) -> Option<(f32, i32)> {
+  let mut prts = sharpen_string.split(',');
+  let sigma = str_to_sigma(prts.next()?, MAX_SHARPEN_SIGMA)?;
+  let threshold = match prts.next() {
+    Some(threshold) => threshold.parse::<u8>().ok()? as i32,
+    None => 0,
+  };
+  if prts.next().is_some() {
+    return None;
+  }
+  Some((sigma, threshold))
+}
+
+fn str_in_range<T: FromStr + PartialOrd>(num_string: &str, range: std::ops::RangeInclusive<T>) -> Option<T> {
+  num_string.parse::<T>().ok().filter(|num| range.contains(num))
+}
+
+// Hex "rrggbb" or "rrggbbaa", optionally with a (percent encoded) leading '#'
+pub fn str_to_colour(colour_string: &str) -> Option<image::Rgba<u8>> {
+  let hex = colour_string.strip_prefix("%23").unwrap_or(colour_string).trim_start_matches('#');
+  if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+    return None;
+  }
+  let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+  let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+  Some(image::Rgba([channel(0)?, channel(2)?, channel(4)?, alpha]))
+}
+
+// Either one value for every edge, or "top,right,bottom,left"
+fn str_to_pad(pad_string: &str) -> Option<(u32, u32, u32, u32)> {
+  let edges = pad_string.split(',')
+    .map(|edge| edge.parse::<u32>().ok())
+    .collect::<Option<Vec<u32>>>()?;
+  match edges[..] {
+    [all] => Some((all, all, all, all)),
+    [top, right, bottom, left] => Some((top, right, bottom, left)),
+    _ => None
+  }
+}
+
+// A colour tolerance, or a bare flag meaning exact matches only
+fn str_to_trim(trim_string: &str) -> Option<Option<u8>> {
+  match trim_string {
+    "true" => Some(Some(0)),
+    "false" => Some(None),
+    tolerance => tolerance.parse::<u8>().ok().map(Some),
+  }
+}
+
+// Formats we will switch to when a client says it can take them,
+// in order of preference
+const NEGOTIABLE_FORMATS: [(image::ImageFormat, &str); 1] = [
+  (image::ImageFormat::WebP, "image/webp")
+];
+
+pub fn format_from_accept(accept: &str) -> Option<image::ImageFormat> {
+  let mut accepted: Vec<&str> = vec![];
+  for media_range in accept.split(',') {
+    let mut prts = media_range.split(';');
+    let mime = prts.next().unwrap_or("").trim();
+    let refused = prts
+      .filter_map(|p| p.trim().strip_prefix("q="))
+      .any(|q| q.parse::<f32>().map(|q| q <= 0.0).unwrap_or(false));
+    if !refused {
+      accepted.push(mime);
+    }
+  }
+  NEGOTIABLE_FORMATS.iter()
+    .find(|(_, mime)| accepted.contains(mime))
+    .map(|(fmt, _)| *fmt)
+}
+
 impl FromStr for ImageParameters {
     type Err = ImageParameterParseError;
 
+    // The first invalid parameter, see parse_all for every one of them
     fn from_str(string: &str) -> Result<Self, Self::Err> {
+      ImageParameters::parse_all(string).map_err(|errors| errors[0])
+    }
+}
+
+impl ImageParameters {
+    // Parses every parameter, collecting all the invalid ones
+    pub fn parse_all(string: &str) -> Result<ImageParameters, Vec<ImageParameterParseError>> {
+      match ImageParameters::parse_valid(string) {
+        (img_params, errors) if errors.is_empty() => Ok(img_params),
+        (_, errors) => Err(errors),
+      }
+    }
+
+    // The parameters that are valid, alongside the errors for those that aren't
+    fn parse_valid(string: &str) -> (ImageParameters, Vec<ImageParameterParseError>) {
       let query_parts = string.split("&");
       let mut params: HashMap<&str, &str> = HashMap::new();
 
-      let mut img_params = ImageParameters { 
-        width: None, 
-        height: None,
-        scaling_filter: None,
-        oversized_handling: None
-      };
+      let mut img_params = ImageParameters::default();
+      let mut errors = vec![];
 
       for q in query_parts {
-          let mut prts = q.split("=").into_iter();
+          let mut prts = q.split('=');
           let key = prts.next().unwrap();
           let val = prts.next().unwrap_or("true");
           params.insert(key, val);
@@ -73,7 +519,7 @@ impl FromStr for ImageParameters {
         if let Ok(num) = num_string.parse::<u32>() {
           img_params.width = Some(num);
         } else {
-          return Err(ImageParameterParseError::WidthParseError);
+          errors.push(ImageParameterParseError::WidthParseError);
         }
       }
 
@@ -81,7 +527,14 @@ impl FromStr for ImageParameters {
         if let Ok(num) = num_string.parse::<u32>() {
           img_params.height = Some(num);
         } else {
-          return Err(ImageParameterParseError::HeightParseError);
+          errors.push(ImageParameterParseError::HeightParseError);
+        }
+      }
+
+      if let Some(dpr_string) = params.get("dpr") {
+        match str_to_dpr(dpr_string) {
+            Ok(dpr) => img_params.dpr = Some(dpr),
+            Err(err) => errors.push(err),
         }
       }
 
@@ -89,19 +542,266 @@ impl FromStr for ImageParameters {
         if let Ok(val) = stng.parse::<OversizedImageHandling>() {
           img_params.oversized_handling = Some(val);
         } else {
-          return Err(ImageParameterParseError::OversizeParseError);
+          errors.push(ImageParameterParseError::OversizeParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("fit") {
+        if let Ok(val) = stng.parse::<FitMode>() {
+          img_params.fit = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::FitParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("gravity") {
+        if let Ok(val) = stng.parse::<Gravity>() {
+          img_params.gravity = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::GravityParseError);
+        }
+      }
+
+      if params.contains_key("fp-x") || params.contains_key("fp-y") {
+        let fp_x = params.get("fp-x").map_or(Some(0.5), |x| str_to_fraction(x));
+        let fp_y = params.get("fp-y").map_or(Some(0.5), |y| str_to_fraction(y));
+        match (fp_x, fp_y) {
+            (Some(x), Some(y)) => img_params.focal_point = Some((x, y)),
+            _ => errors.push(ImageParameterParseError::FocalPointParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("rotate") {
+        if let Ok(val) = stng.parse::<Rotation>() {
+          img_params.rotate = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::RotateParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("flip") {
+        if let Ok(val) = stng.parse::<Flip>() {
+          img_params.flip = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::FlipParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("blur") {
+        match str_to_sigma(stng, MAX_BLUR_SIGMA) {
+            Some(sigma) => img_params.blur = Some(sigma),
+            None => errors.push(ImageParameterParseError::BlurParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("sharpen") {
+        match str_to_sharpen(stng) {
+            Some(sharpen) => img_params.sharpen = Some(sharpen),
+            None => errors.push(ImageParameterParseError::SharpenParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("brightness") {
+        match str_in_range(stng, -255..=255) {
+            Some(brightness) => img_params.brightness = Some(brightness),
+            None => errors.push(ImageParameterParseError::BrightnessParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("contrast") {
+        match str_in_range(stng, -100.0..=100.0) {
+            Some(contrast) => img_params.contrast = Some(contrast),
+            None => errors.push(ImageParameterParseError::ContrastParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("hue") {
+        match str_in_range(stng, -360..=360) {
+            Some(hue) => img_params.hue = Some(hue),
+            None => errors.push(ImageParameterParseError::HueParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("saturation") {
+        match str_in_range(stng, 0.0..=3.0) {
+            Some(saturation) => img_params.saturation = Some(saturation),
+            None => errors.push(ImageParameterParseError::SaturationParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("grayscale") {
+        if let Ok(val) = stng.parse::<bool>() {
+          img_params.grayscale = val;
+        } else {
+          errors.push(ImageParameterParseError::GrayscaleParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("invert") {
+        if let Ok(val) = stng.parse::<bool>() {
+          img_params.invert = val;
+        } else {
+          errors.push(ImageParameterParseError::InvertParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("bg") {
+        match str_to_colour(stng) {
+            Some(colour) => img_params.background = Some(colour),
+            None => errors.push(ImageParameterParseError::BackgroundParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("pad") {
+        match str_to_pad(stng) {
+            Some(pad) => img_params.pad = Some(pad),
+            None => errors.push(ImageParameterParseError::PadParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("wm") {
+        if stng.starts_with('/') {
+          img_params.watermark = Some(stng.to_string());
+        } else {
+          errors.push(ImageParameterParseError::WatermarkParseError);
+        }
+      }
+
+      if let Some(stng) = params.get("trim") {
+        match str_to_trim(stng) {
+            Some(trim) => img_params.trim = trim,
+            None => errors.push(ImageParameterParseError::TrimParseError),
+        }
+      }
+
+      if let Some(stng) = params.get("crop") {
+        if let Ok(val) = stng.parse::<CropRegion>() {
+          img_params.crop = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::CropParseError);
         }
       }
 
       if let Some(filter_string) = params.get("filter") {
         match str_to_filter(filter_string) {
             Ok(flt) => img_params.scaling_filter = Some(flt),
-            Err(err) => return Err(err),
+            Err(err) => errors.push(err),
+        }
+      }
+
+      if let Some(format_string) = params.get("format") {
+        match str_to_format(format_string) {
+            Ok(fmt) => img_params.format = Some(fmt),
+            Err(err) => errors.push(err),
+        }
+      }
+
+      if let Some(quality_string) = params.get("quality") {
+        match str_to_quality(quality_string) {
+            Ok(quality) => img_params.quality = Some(quality),
+            Err(err) => errors.push(err),
+        }
+      }
+
+      if let Some(stng) = params.get("lossless") {
+        if let Ok(val) = stng.parse::<bool>() {
+          img_params.lossless = Some(val);
+        } else {
+          errors.push(ImageParameterParseError::LosslessParseError);
+        }
+      }
+
+      if let Some(compression_string) = params.get("compression") {
+        match str_to_compression(compression_string) {
+            Ok(compression) => img_params.png_compression = Some(compression),
+            Err(err) => errors.push(err),
         }
       }
 
-      Ok(img_params)
+      if let Some(filter_string) = params.get("pngfilter") {
+        match str_to_png_filter(filter_string) {
+            Ok(flt) => img_params.png_filter = Some(flt),
+            Err(err) => errors.push(err),
+        }
+      }
+
+      (img_params, errors)
+    }
+}
+
+// A requested size held to the allowed sizes, which are sorted: only exact
+// matches, or rounded up to the next allowed size when bucketing.
+// Any size is fine when none are set
+pub fn allowed_size(size: u32, allowed: &[u32], bucketing: bool) -> Option<u32> {
+  if allowed.is_empty() {
+    return Some(size);
+  }
+  if bucketing {
+    allowed.iter().copied().find(|allowed| *allowed >= size)
+  } else {
+    allowed.contains(&size).then_some(size)
+  }
+}
+
+// A dimension's final size, in device pixels with its padding, held to
+// the allowed sizes. Gives back the size to request before padding, and
+// padding alone can't be held to anything without a size to pad
+fn allowed_target(target: Option<u32>, padding: u32, allowed: &[u32], bucketing: bool) -> Result<Option<u32>, ()> {
+  if allowed.is_empty() {
+    return Ok(target);
+  }
+  match target {
+    Some(target) => allowed_size(target.saturating_add(padding), allowed, bucketing).map(|size| Some(size - padding)).ok_or(()),
+    None if padding == 0 => Ok(None),
+    None => Err(()),
+  }
+}
+
+impl ImageParameters {
+  // Parses a request's query, holding the width and height to the
+  // configured output sizes
+  pub fn from_query(query: &str, settings: &ImgprssrConfig) -> Result<ImageParameters, Vec<ImageParameterParseError>> {
+    let (mut params, mut errors) = ImageParameters::parse_valid(query);
+    errors.append(&mut params.hold_to_allowed_sizes(settings));
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    Ok(params)
+  }
+
+  // Holds the size that will actually come out, after dpr and padding, to
+  // the configured output sizes. An explicit dpr is folded into the sizes
+  // so a size rounded up by bucketing is exactly the size produced.
+  // Needs running again whenever client hints change the size
+  pub fn hold_to_allowed_sizes(&mut self, settings: &ImgprssrConfig) -> Vec<ImageParameterParseError> {
+    if settings.allowed_widths.is_empty() && settings.allowed_heights.is_empty() {
+      return vec![];
     }
+    let (target_width, target_height) = crate::process::target_size(settings, self);
+    let (top, right, bottom, left) = self.pad.unwrap_or((0, 0, 0, 0));
+    let width = allowed_target(target_width, left.saturating_add(right), &settings.allowed_widths, settings.size_bucketing);
+    let height = allowed_target(target_height, top.saturating_add(bottom), &settings.allowed_heights, settings.size_bucketing);
+    match (width, height) {
+      (Ok(width), Ok(height)) => {
+        self.width = width;
+        self.height = height;
+        if self.dpr.is_some() {
+          self.dpr = Some(1.0);
+        }
+        vec![]
+      },
+      (width, height) => {
+        let mut errors = vec![];
+        if width.is_err() {
+          errors.push(ImageParameterParseError::WidthNotAllowedError);
+        }
+        if height.is_err() {
+          errors.push(ImageParameterParseError::HeightNotAllowedError);
+        }
+        errors
+      },
+    }
+  }
 }
 
 #[cfg(test)]
@@ -115,7 +815,8 @@ mod tests {
       width: None, 
       height: None,
       scaling_filter: None,
-      oversized_handling: None
+      oversized_handling: None,
+      ..Default::default()
     });
   }
 
@@ -128,7 +829,8 @@ mod tests {
         width: Some(width), 
         height: None,
         scaling_filter: None,
-        oversized_handling: None
+        oversized_handling: None,
+        ..Default::default()
       });
     }
   }
@@ -151,7 +853,8 @@ mod tests {
         width: None,
         height: Some(height),
         scaling_filter: None,
-        oversized_handling: None
+        oversized_handling: None,
+        ..Default::default()
       });
     }
   }
@@ -181,7 +884,8 @@ mod tests {
         width: None, 
         height: None,
         scaling_filter: Some(filter_type),
-        oversized_handling: None
+        oversized_handling: None,
+        ..Default::default()
       });
     }
   }
@@ -190,6 +894,8 @@ mod tests {
   fn parses_oversize_handling() {
     let cases = [
       (OversizedImageHandling::Clamp, "clamp"),
+      (OversizedImageHandling::Upscale, "upscale"),
+      (OversizedImageHandling::Reject, "reject"),
     ];
     for (filter_type, filter_string) in cases {
       let test: ImageParameters = format!("oversizehandling={}", filter_string).parse().unwrap();
@@ -197,7 +903,8 @@ mod tests {
         width: None, 
         height: None,
         scaling_filter: None,
-        oversized_handling: Some(filter_type)
+        oversized_handling: Some(filter_type),
+        ..Default::default()
       });
     }
   }
@@ -218,4 +925,534 @@ mod tests {
     let test: Result<ImageParameters, ImageParameterParseError> = "filter=notreal".parse();
     assert_eq!(test, Err(ImageParameterParseError::FilterParseError));
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn parses_formats() {
+    let cases = [
+      (image::ImageFormat::Png, "png"),
+      (image::ImageFormat::Jpeg, "jpeg"),
+      (image::ImageFormat::Jpeg, "jpg"),
+      (image::ImageFormat::WebP, "webp"),
+      (image::ImageFormat::Gif, "gif"),
+      (image::ImageFormat::Bmp, "bmp"),
+      (image::ImageFormat::Tiff, "tiff"),
+      (image::ImageFormat::Ico, "ico"),
+      (image::ImageFormat::Tga, "tga")
+    ];
+    for (format, format_string) in cases {
+      let test: ImageParameters = format!("format={}", format_string).parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        format: Some(format),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn non_existent_format_returns_error() {
+    let cases = ["format=notreal", "format=avif", "format=", "format"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::FormatParseError));
+    }
+  }
+
+  #[test]
+  fn negotiates_format_from_accept() {
+    let cases = [
+      ("image/webp", Some(image::ImageFormat::WebP)),
+      ("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8", Some(image::ImageFormat::WebP)),
+      ("image/png, image/webp;q=0.5", Some(image::ImageFormat::WebP)),
+      ("image/webp;q=0", None),
+      ("image/png,image/*;q=0.8", None),
+      ("*/*", None),
+      ("", None)
+    ];
+    for (accept, expected) in cases {
+      assert_eq!(format_from_accept(accept), expected);
+    }
+  }
+
+  #[test]
+  fn parses_quality() {
+    let cases = [1_u8, 50, 70, 92, 100];
+    for quality in cases {
+      let test: ImageParameters = format!("quality={}", quality).parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        quality: Some(quality),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn invalid_quality_returns_err() {
+    let cases = ["quality=0", "quality=101", "quality=-1", "quality=high", "quality=", "quality"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::QualityParseError));
+    }
+  }
+
+  #[test]
+  fn parses_lossless() {
+    let cases = [(true, "lossless"), (true, "lossless=true"), (false, "lossless=false")];
+    for (lossless, case) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        lossless: Some(lossless),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn invalid_lossless_returns_err() {
+    let test: Result<ImageParameters, ImageParameterParseError> = "lossless=yes".parse();
+    assert_eq!(test, Err(ImageParameterParseError::LosslessParseError));
+  }
+
+  #[test]
+  fn parses_png_encoding_options() {
+    let test: ImageParameters = "compression=best&pngfilter=paeth".parse().unwrap();
+    assert_eq!(test, ImageParameters { 
+      png_compression: Some(image::codecs::png::CompressionType::Best),
+      png_filter: Some(image::codecs::png::FilterType::Paeth),
+      ..Default::default()
+    });
+  }
+
+  #[test]
+  fn invalid_png_encoding_options_return_err() {
+    let test: Result<ImageParameters, ImageParameterParseError> = "compression=huffman".parse();
+    assert_eq!(test, Err(ImageParameterParseError::CompressionParseError));
+    let test: Result<ImageParameters, ImageParameterParseError> = "pngfilter=notreal".parse();
+    assert_eq!(test, Err(ImageParameterParseError::PngFilterParseError));
+  }
+
+  #[test]
+  fn parses_fit() {
+    let cases = [
+      (FitMode::Cover, "cover"),
+      (FitMode::Contain, "contain"),
+      (FitMode::Fill, "fill"),
+      (FitMode::Inside, "inside"),
+      (FitMode::Outside, "outside")
+    ];
+    for (fit, fit_string) in cases {
+      let test: ImageParameters = format!("fit={}", fit_string).parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        fit: Some(fit),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_fit() {
+    let cases = ["fit=stretch", "fit=", "fit"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::FitParseError));
+    }
+  }
+
+  #[test]
+  fn parses_gravity() {
+    let cases = [
+      (Gravity::Centre, "centre"),
+      (Gravity::Centre, "center"),
+      (Gravity::North, "north"),
+      (Gravity::NorthEast, "northeast"),
+      (Gravity::East, "east"),
+      (Gravity::SouthEast, "southeast"),
+      (Gravity::South, "south"),
+      (Gravity::SouthWest, "southwest"),
+      (Gravity::West, "west"),
+      (Gravity::NorthWest, "northwest"),
+      (Gravity::Smart, "smart")
+    ];
+    for (gravity, gravity_string) in cases {
+      let test: ImageParameters = format!("gravity={}", gravity_string).parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        gravity: Some(gravity),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_gravity() {
+    let test: Result<ImageParameters, ImageParameterParseError> = "gravity=up".parse();
+    assert_eq!(test, Err(ImageParameterParseError::GravityParseError));
+  }
+
+  #[test]
+  fn parses_focal_point() {
+    let cases = [
+      ("fp-x=0.25&fp-y=0.75", (0.25, 0.75)),
+      ("fp-x=0&fp-y=1", (0.0, 1.0)),
+      ("fp-x=0.1", (0.1, 0.5)),
+      ("fp-y=0.9", (0.5, 0.9))
+    ];
+    for (case, focal_point) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        focal_point: Some(focal_point),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_focal_point() {
+    let cases = ["fp-x=1.5", "fp-y=-0.1", "fp-x=left", "fp-x=0.5&fp-y=", "fp-y"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::FocalPointParseError));
+    }
+  }
+
+  #[test]
+  fn parses_crop() {
+    let cases = [
+      ("crop=10,20,300,200", CropRegion::Pixels(10, 20, 300, 200)),
+      ("crop=0,0,1,1", CropRegion::Pixels(0, 0, 1, 1)),
+      ("crop=0.1,0.2,0.5,0.5", CropRegion::Fractions(0.1, 0.2, 0.5, 0.5)),
+      ("crop=0,0,0.5,1", CropRegion::Fractions(0.0, 0.0, 0.5, 1.0))
+    ];
+    for (case, crop) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        crop: Some(crop),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_crop() {
+    let cases = ["crop=10,20,300", "crop=10,20,300,200,1", "crop=-1,0,10,10", "crop=0,0,1.5,0.5", "crop=a,b,c,d", "crop=", "crop"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::CropParseError));
+    }
+  }
+
+  #[test]
+  fn parses_dpr() {
+    let cases = [(1.0_f32, "1"), (1.5, "1.5"), (2.0, "2"), (3.0, "3"), (0.5, "0.5")];
+    for (dpr, dpr_string) in cases {
+      let test: ImageParameters = format!("dpr={}", dpr_string).parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        dpr: Some(dpr),
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn invalid_dpr_returns_err() {
+    let cases = ["dpr=0", "dpr=-1", "dpr=11", "dpr=NaN", "dpr=inf", "dpr=2x", "dpr=", "dpr"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::DprParseError));
+    }
+  }
+
+  #[test]
+  fn parses_rotate_and_flip() {
+    let cases = [
+      ("rotate=90", Some(Rotation::Rotate90), None),
+      ("rotate=180", Some(Rotation::Rotate180), None),
+      ("rotate=270", Some(Rotation::Rotate270), None),
+      ("flip=h", None, Some(Flip::Horizontal)),
+      ("flip=v", None, Some(Flip::Vertical)),
+      ("flip=hv", None, Some(Flip::Both)),
+      ("rotate=90&flip=vh", Some(Rotation::Rotate90), Some(Flip::Both))
+    ];
+    for (case, rotate, flip) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        rotate,
+        flip,
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_rotate_and_flip() {
+    let cases = [
+      ("rotate=45", ImageParameterParseError::RotateParseError),
+      ("rotate=-90", ImageParameterParseError::RotateParseError),
+      ("rotate", ImageParameterParseError::RotateParseError),
+      ("flip=x", ImageParameterParseError::FlipParseError),
+      ("flip", ImageParameterParseError::FlipParseError)
+    ];
+    for (case, err) in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(err));
+    }
+  }
+
+  #[test]
+  fn parses_blur_and_sharpen() {
+    let cases = [
+      ("blur=2", Some(2.0), None),
+      ("blur=0.5", Some(0.5), None),
+      ("blur=20", Some(20.0), None),
+      ("sharpen=1.5", None, Some((1.5, 0))),
+      ("sharpen=1,10", None, Some((1.0, 10))),
+      ("sharpen=10,255", None, Some((10.0, 255)))
+    ];
+    for (case, blur, sharpen) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        blur,
+        sharpen,
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_blur_and_sharpen() {
+    let cases = [
+      ("blur=0", ImageParameterParseError::BlurParseError),
+      ("blur=20.5", ImageParameterParseError::BlurParseError),
+      ("blur=-1", ImageParameterParseError::BlurParseError),
+      ("blur", ImageParameterParseError::BlurParseError),
+      ("sharpen=11", ImageParameterParseError::SharpenParseError),
+      ("sharpen=1,256", ImageParameterParseError::SharpenParseError),
+      ("sharpen=1,-1", ImageParameterParseError::SharpenParseError),
+      ("sharpen=1,2,3", ImageParameterParseError::SharpenParseError),
+      ("sharpen=", ImageParameterParseError::SharpenParseError)
+    ];
+    for (case, err) in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(err));
+    }
+  }
+
+  #[test]
+  fn parses_colour_adjustments() {
+    let test: ImageParameters = "brightness=-40&contrast=25.5&hue=180&saturation=1.5&grayscale&invert=true".parse().unwrap();
+    assert_eq!(test, ImageParameters { 
+      brightness: Some(-40),
+      contrast: Some(25.5),
+      hue: Some(180),
+      saturation: Some(1.5),
+      grayscale: true,
+      invert: true,
+      ..Default::default()
+    });
+    let test: ImageParameters = "grayscale=false&invert=false".parse().unwrap();
+    assert_eq!(test, ImageParameters::default());
+  }
+
+  #[test]
+  fn errors_colour_adjustments() {
+    let cases = [
+      ("brightness=256", ImageParameterParseError::BrightnessParseError),
+      ("brightness=1.5", ImageParameterParseError::BrightnessParseError),
+      ("contrast=-101", ImageParameterParseError::ContrastParseError),
+      ("contrast=NaN", ImageParameterParseError::ContrastParseError),
+      ("hue=361", ImageParameterParseError::HueParseError),
+      ("saturation=-0.5", ImageParameterParseError::SaturationParseError),
+      ("saturation=3.5", ImageParameterParseError::SaturationParseError),
+      ("grayscale=yes", ImageParameterParseError::GrayscaleParseError),
+      ("invert=1", ImageParameterParseError::InvertParseError)
+    ];
+    for (case, err) in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(err));
+    }
+  }
+
+  #[test]
+  fn parses_background_and_pad() {
+    let cases = [
+      ("bg=ffffff", Some(image::Rgba([255, 255, 255, 255])), None),
+      ("bg=%23FF8000", Some(image::Rgba([255, 128, 0, 255])), None),
+      ("bg=00000080", Some(image::Rgba([0, 0, 0, 128])), None),
+      ("pad=10", None, Some((10, 10, 10, 10))),
+      ("pad=1,2,3,4", None, Some((1, 2, 3, 4))),
+      ("bg=abcdef&pad=0,5,0,5", Some(image::Rgba([171, 205, 239, 255])), Some((0, 5, 0, 5)))
+    ];
+    for (case, background, pad) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        background,
+        pad,
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_background_and_pad() {
+    let cases = [
+      ("bg=fff", ImageParameterParseError::BackgroundParseError),
+      ("bg=gggggg", ImageParameterParseError::BackgroundParseError),
+      ("bg=ffffff0", ImageParameterParseError::BackgroundParseError),
+      ("bg=+fffff", ImageParameterParseError::BackgroundParseError),
+      ("bg", ImageParameterParseError::BackgroundParseError),
+      ("pad=1,2", ImageParameterParseError::PadParseError),
+      ("pad=-1", ImageParameterParseError::PadParseError),
+      ("pad=", ImageParameterParseError::PadParseError)
+    ];
+    for (case, err) in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(err));
+    }
+  }
+
+  #[test]
+  fn parses_watermark() {
+    let test: ImageParameters = "wm=/logos/stamp.png".parse().unwrap();
+    assert_eq!(test, ImageParameters { 
+      watermark: Some("/logos/stamp.png".to_owned()),
+      ..Default::default()
+    });
+  }
+
+  #[test]
+  fn errors_watermark() {
+    let cases = ["wm=stamp.png", "wm=", "wm"];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::WatermarkParseError));
+    }
+  }
+
+  #[test]
+  fn parses_trim() {
+    let cases = [
+      ("trim", Some(0)),
+      ("trim=true", Some(0)),
+      ("trim=false", None),
+      ("trim=0", Some(0)),
+      ("trim=24", Some(24)),
+      ("trim=255", Some(255))
+    ];
+    for (case, trim) in cases {
+      let test: ImageParameters = case.parse().unwrap();
+      assert_eq!(test, ImageParameters { 
+        trim,
+        ..Default::default()
+      });
+    }
+  }
+
+  #[test]
+  fn errors_trim() {
+    let cases = ["trim=256", "trim=-1", "trim=yes", "trim="];
+    for case in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test, Err(ImageParameterParseError::TrimParseError));
+    }
+  }
+
+  #[test]
+  fn allowed_sizes_match_exactly_or_round_up() {
+    let allowed = [100, 200, 400];
+    let cases = [
+      (100, false, Some(100)),
+      (150, false, None),
+      (500, false, None),
+      (1, true, Some(100)),
+      (100, true, Some(100)),
+      (150, true, Some(200)),
+      (201, true, Some(400)),
+      (401, true, None)
+    ];
+    for (size, bucketing, expected) in cases {
+      assert_eq!(allowed_size(size, &allowed, bucketing), expected);
+    }
+    assert_eq!(allowed_size(123, &[], false), Some(123));
+  }
+
+  #[test]
+  fn from_query_enforces_allowed_sizes() {
+    let settings = ImgprssrConfig { allowed_widths: vec![100, 200], allowed_heights: vec![50], ..Default::default() };
+    let test = ImageParameters::from_query("width=200&height=50&filter=gaussian", &settings);
+    assert_eq!(test, Ok(ImageParameters {
+      width: Some(200),
+      height: Some(50),
+      scaling_filter: Some(image::imageops::FilterType::Gaussian),
+      ..Default::default()
+    }));
+    assert_eq!(ImageParameters::from_query("width=150", &settings), Err(vec![ImageParameterParseError::WidthNotAllowedError]));
+    assert_eq!(ImageParameters::from_query("height=60", &settings), Err(vec![ImageParameterParseError::HeightNotAllowedError]));
+    assert_eq!(ImageParameters::from_query("width=wide", &settings), Err(vec![ImageParameterParseError::WidthParseError]));
+    assert_eq!(ImageParameters::from_query("width=wide&height=60", &settings), Err(vec![
+      ImageParameterParseError::WidthParseError,
+      ImageParameterParseError::HeightNotAllowedError
+    ]));
+
+    let settings = ImgprssrConfig { allowed_widths: vec![100, 200], size_bucketing: true, ..Default::default() };
+    let test = ImageParameters::from_query("width=101&height=77", &settings);
+    assert_eq!(test, Ok(ImageParameters { width: Some(200), height: Some(77), ..Default::default() }));
+    assert_eq!(ImageParameters::from_query("width=201", &settings), Err(vec![ImageParameterParseError::WidthNotAllowedError]));
+  }
+
+  #[test]
+  fn allowed_sizes_cover_dpr_and_padding() {
+    let settings = ImgprssrConfig { allowed_widths: vec![100, 200, 400], allowed_heights: vec![50, 100], ..Default::default() };
+    assert_eq!(ImageParameters::from_query("width=100&dpr=2", &settings), Ok(ImageParameters { width: Some(200), dpr: Some(1.0), ..Default::default() }));
+    assert_eq!(ImageParameters::from_query("width=100&dpr=2.37", &settings), Err(vec![ImageParameterParseError::WidthNotAllowedError]));
+    assert_eq!(ImageParameters::from_query("width=100&height=50&dpr=3", &settings), Err(vec![
+      ImageParameterParseError::WidthNotAllowedError,
+      ImageParameterParseError::HeightNotAllowedError
+    ]));
+    assert_eq!(ImageParameters::from_query("width=190&pad=0,5,0,5", &settings), Ok(ImageParameters { width: Some(190), pad: Some((0, 5, 0, 5)), ..Default::default() }));
+    assert_eq!(ImageParameters::from_query("width=100&pad=0,5,0,5", &settings), Err(vec![ImageParameterParseError::WidthNotAllowedError]));
+    assert_eq!(ImageParameters::from_query("pad=10", &settings), Err(vec![
+      ImageParameterParseError::WidthNotAllowedError,
+      ImageParameterParseError::HeightNotAllowedError
+    ]));
+
+    let settings = ImgprssrConfig { size_bucketing: true, ..settings };
+    let test = ImageParameters::from_query("width=100&dpr=2.37", &settings);
+    assert_eq!(test, Ok(ImageParameters { width: Some(400), dpr: Some(1.0), ..Default::default() }));
+    let test = ImageParameters::from_query("width=120&pad=0,5,0,5", &settings);
+    assert_eq!(test, Ok(ImageParameters { width: Some(190), pad: Some((0, 5, 0, 5)), ..Default::default() }));
+    assert_eq!(ImageParameters::from_query("width=200&dpr=2.5", &settings), Err(vec![ImageParameterParseError::WidthNotAllowedError]));
+    // Bucketing leaves the dpr for client hints to fill in when the query has none
+    assert_eq!(ImageParameters::from_query("width=150", &settings), Ok(ImageParameters { width: Some(200), ..Default::default() }));
+  }
+
+  #[test]
+  fn parse_all_reports_every_invalid_parameter() {
+    let test = ImageParameters::parse_all("width=wide&height=100&format=jxl&blur=0&bg=red&fit=cover");
+    assert_eq!(test, Err(vec![
+      ImageParameterParseError::WidthParseError,
+      ImageParameterParseError::BlurParseError,
+      ImageParameterParseError::BackgroundParseError,
+      ImageParameterParseError::FormatParseError
+    ]));
+    let test: Result<ImageParameters, ImageParameterParseError> = "width=wide&height=100&format=jxl&blur=0&bg=red&fit=cover".parse();
+    assert_eq!(test, Err(ImageParameterParseError::WidthParseError));
+    assert_eq!(ImageParameters::parse_all("width=100&fit=cover"), Ok(ImageParameters {
+      width: Some(100),
+      fit: Some(FitMode::Cover),
+      ..Default::default()
+    }));
+  }
+
+  #[test]
+  fn errors_name_their_parameter() {
+    let cases = [
+      ("width=wide", "width"),
+      ("fp-x=2", "fp-x, fp-y"),
+      ("oversizehandling=shrink", "oversizehandling"),
+      ("bg=red", "bg"),
+      ("wm=logo.png", "wm")
+    ];
+    for (case, parameter) in cases {
+      let test: Result<ImageParameters, ImageParameterParseError> = case.parse();
+      assert_eq!(test.unwrap_err().parameter(), parameter);
+    }
+  }
+}
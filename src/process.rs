@@ -1,41 +1,170 @@
-use std::io::{Cursor, Read};
-use image::DynamicImage;
+use std::io::{Cursor, Write};
+use image::{DynamicImage, GenericImageView, ImageResult, codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder}, error::{EncodingError, ImageError, ImageFormatHint}};
 
-use crate::{appconfig::ImgprssrConfig, parameters::OversizedImageHandling};
+use crate::{appconfig::ImgprssrConfig, error::ImgprssrError, parameters::{CropRegion, FitMode, Flip, Gravity, ImageParameters, OversizedImageHandling, Rotation}};
 
-pub fn process_image_to_buffer(settings: &ImgprssrConfig, mut img: DynamicImage, img_format: image::ImageFormat, params: crate::parameters::ImageParameters) -> Vec<u8> {
-  img = process_image(settings, img, params);
+struct EncoderSettings {
+  quality: Option<u8>,
+  lossless: bool,
+  png_compression: image::codecs::png::CompressionType,
+  png_filter: image::codecs::png::FilterType
+}
+
+impl EncoderSettings {
+  fn from_parameters(settings: &ImgprssrConfig, params: &ImageParameters) -> EncoderSettings {
+    let quality = params.quality.or(settings.default_quality);
+    // Asking for a quality only makes sense for lossy output
+    let lossless = params.lossless.unwrap_or(params.quality.is_none() && settings.default_lossless);
+    EncoderSettings {
+      quality,
+      lossless,
+      png_compression: params.png_compression.unwrap_or(settings.default_png_compression),
+      png_filter: params.png_filter.unwrap_or(settings.default_png_filter)
+    }
+  }
+}
+
+pub fn process_image_to_buffer(settings: &ImgprssrConfig, mut img: DynamicImage, img_format: image::ImageFormat, params: ImageParameters, watermark: Option<DynamicImage>) -> Result<Vec<u8>, ImgprssrError> {
+  let encoder_settings = EncoderSettings::from_parameters(settings, &params);
+  let background = params.background;
+  img = process_image(settings, img, params, watermark);
+  if let Some(colour) = background {
+    img = flatten_for_format(img, img_format, colour);
+  }
+  img = prepare_for_format(img, img_format);
   let mut buffer = Cursor::new(Vec::new());
-  img.write_to(&mut buffer, img_format).unwrap();
-  let mut out = Vec::new();
-  buffer.set_position(0);
-  buffer.read_to_end(&mut out).unwrap();
-  out
+  encode_image(&img, img_format, encoder_settings, &mut buffer).map_err(|err| ImgprssrError::Encode(err.to_string()))?;
+  Ok(buffer.into_inner())
+}
+
+fn encode_image(img: &DynamicImage, img_format: image::ImageFormat, encoder_settings: EncoderSettings, buffer: &mut Cursor<Vec<u8>>) -> ImageResult<()> {
+  match img_format {
+    image::ImageFormat::Jpeg => {
+      let quality = encoder_settings.quality.unwrap_or(75);
+      img.write_with_encoder(JpegEncoder::new_with_quality(buffer, quality))
+    },
+    image::ImageFormat::WebP if encoder_settings.lossless => img.write_with_encoder(WebPEncoder::new_lossless(buffer)),
+    image::ImageFormat::WebP => encode_lossy_webp(img, encoder_settings.quality.unwrap_or(80), buffer),
+    image::ImageFormat::Png => img.write_with_encoder(PngEncoder::new_with_quality(buffer, encoder_settings.png_compression, encoder_settings.png_filter)),
+    _ => img.write_to(buffer, img_format),
+  }
+}
+
+// The image crate only keeps a lossless WebP encoder going forward,
+// so lossy output goes to libwebp directly
+fn encode_lossy_webp(img: &DynamicImage, quality: u8, buffer: &mut Cursor<Vec<u8>>) -> ImageResult<()> {
+  let encoder = match img {
+    DynamicImage::ImageRgb8(rgb) => webp::Encoder::from_rgb(rgb.as_raw(), img.width(), img.height()),
+    DynamicImage::ImageRgba8(rgba) => webp::Encoder::from_rgba(rgba.as_raw(), img.width(), img.height()),
+    // prepare_for_format only ever hands over 8 bit RGB(A)
+    _ => return Err(ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(image::ImageFormat::WebP), "expected 8 bit RGB or RGBA"))),
+  };
+  let encoded = encoder.encode_simple(false, quality as f32)
+    .map_err(|err| ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(image::ImageFormat::WebP), format!("{err:?}"))))?;
+  buffer.write_all(&encoded)?;
+  Ok(())
+}
+
+// Blends any transparency onto the background colour when
+// the target format can't hold an alpha channel
+fn flatten_for_format(img: DynamicImage, img_format: image::ImageFormat, background: image::Rgba<u8>) -> DynamicImage {
+  if img_format != image::ImageFormat::Jpeg || !img.color().has_alpha() {
+    return img;
+  }
+  let mut buffer = image::RgbImage::new(img.width(), img.height());
+  for (pixel, source) in buffer.pixels_mut().zip(img.to_rgba8().pixels()) {
+    let alpha = source[3] as u32;
+    let blend = |i: usize| ((source[i] as u32 * alpha + background[i] as u32 * (255 - alpha) + 127) / 255) as u8;
+    *pixel = image::Rgb([blend(0), blend(1), blend(2)]);
+  }
+  DynamicImage::ImageRgb8(buffer)
+}
+
+// Not every encoder accepts every colour type, so normalise
+// to something the target format can actually write
+fn prepare_for_format(img: DynamicImage, img_format: image::ImageFormat) -> DynamicImage {
+  match (img_format, &img) {
+    (image::ImageFormat::Jpeg, DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_)) => img,
+    (image::ImageFormat::Jpeg, _) => DynamicImage::ImageRgb8(img.to_rgb8()),
+    (image::ImageFormat::WebP, DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_)) => img,
+    (image::ImageFormat::WebP, _) if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+    (image::ImageFormat::WebP, _) => DynamicImage::ImageRgb8(img.to_rgb8()),
+    _ => img,
+  }
 }
 
 fn resize_fit_height(img: DynamicImage, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
-  let source_height = img.height();
-  if oversize_handling == OversizedImageHandling::Clamp && source_height > height {
-    let height_factor = source_height as f32 / height as f32;
-    let nwidth = img.width() as f32 * height_factor;
-    return img.resize(nwidth as u32, height, scaling_filter);
+  if oversize_handling == OversizedImageHandling::Upscale || img.height() > height {
+    return img.resize(u32::MAX, height, scaling_filter);
   }
   img
 }
 
 fn resize_fit_width(img: DynamicImage, width: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
-  let source_width = img.width();
-  if oversize_handling == OversizedImageHandling::Clamp && source_width > width {
-    let width_factor = source_width as f32 / width as f32;
-    let nheight = img.height() as f32 * width_factor;
-    return img.resize(width, nheight as u32, scaling_filter);
+  if oversize_handling == OversizedImageHandling::Upscale || img.width() > width {
+    return img.resize(width, u32::MAX, scaling_filter);
   }
   img
 }
 
 
-fn fit_to_set_size(mut img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
-  if oversize_handling == OversizedImageHandling::Clamp && !(img.height() >= height) || !(img.width() >= width) {
+// Longest edge of the copy smart gravity searches over
+const SMART_SEARCH_SIZE: u32 = 64;
+
+// Picks the crop window with the most edge energy on a downscaled copy
+// of the image, returning its centre as fractions of width and height
+pub fn smart_focal_point(img: &DynamicImage, width: u32, height: u32) -> (f32, f32) {
+  let small = img.thumbnail(SMART_SEARCH_SIZE, SMART_SEARCH_SIZE).to_luma8();
+  let (small_width, small_height) = small.dimensions();
+  if small_width < 2 || small_height < 2 {
+    return (0.5, 0.5);
+  }
+  let luma = |x: u32, y: u32| small.get_pixel(x, y)[0] as i32;
+  let mut column_energy = vec![0_u64; small_width as usize];
+  let mut row_energy = vec![0_u64; small_height as usize];
+  for y in 0..small_height {
+    for x in 0..small_width {
+      let dx = luma(x.min(small_width - 2) + 1, y) - luma(x.min(small_width - 2), y);
+      let dy = luma(x, y.min(small_height - 2) + 1) - luma(x, y.min(small_height - 2));
+      let energy = (dx.abs() + dy.abs()) as u64;
+      column_energy[x as usize] += energy;
+      row_energy[y as usize] += energy;
+    }
+  }
+
+  let target_aspect = width as f32 / height as f32;
+  let source_aspect = small_width as f32 / small_height as f32;
+  // Only one axis gets cropped, so slide the window along that one
+  let (energy, window) = if target_aspect > source_aspect {
+    (row_energy, ((small_width as f32 / target_aspect).round() as usize).clamp(1, small_height as usize))
+  } else {
+    (column_energy, ((small_height as f32 * target_aspect).round() as usize).clamp(1, small_width as usize))
+  };
+  let centre_offset = (energy.len() - window) as f32 / 2.0;
+  let best_offset = (0..=energy.len() - window)
+    .map(|offset| (offset, energy[offset..offset + window].iter().sum::<u64>()))
+    // Highest energy, falling back to whichever is nearest the centre
+    .max_by(|(a_offset, a_energy), (b_offset, b_energy)| {
+      a_energy.cmp(b_energy).then_with(|| {
+        let a_distance = (*a_offset as f32 - centre_offset).abs();
+        let b_distance = (*b_offset as f32 - centre_offset).abs();
+        b_distance.partial_cmp(&a_distance).unwrap_or(std::cmp::Ordering::Equal)
+      })
+    })
+    .map_or(0, |(offset, _)| offset);
+  let focus = (best_offset as f32 + window as f32 / 2.0) / energy.len() as f32;
+  if target_aspect > source_aspect { (0.5, focus) } else { (focus, 0.5) }
+}
+
+// Offset of the crop window along one edge, centred on the focal point
+// as far as the edges of the image allow
+fn crop_offset(pixels_to_trim: u32, source_length: u32, target_length: u32, focal_point: f32) -> u32 {
+  let centred = focal_point * source_length as f32 - target_length as f32 / 2.0;
+  (centred.round().max(0.0) as u32).min(pixels_to_trim)
+}
+
+fn fit_to_set_size(mut img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling, (focal_x, focal_y): (f32, f32)) -> DynamicImage {
+  if oversize_handling != OversizedImageHandling::Upscale && (img.height() < height || img.width() < width) {
     return img;
   }
   
@@ -45,27 +174,390 @@ fn fit_to_set_size(mut img: DynamicImage, width: u32, height: u32, scaling_filte
   if target_aspect > source_aspect { // "letterboxing" - resize to target width first
     img = resize_fit_width(img, width, scaling_filter, oversize_handling);
     let pixels_to_trim = img.height() - height;
-    let edge_width_to_trim = pixels_to_trim / 2;
+    let edge_width_to_trim = crop_offset(pixels_to_trim, img.height(), height, focal_y);
     img.crop(0, edge_width_to_trim, width, height)
   } else { // "tall" - resize to target height first
     img = resize_fit_height(img, height, scaling_filter, oversize_handling);
     let pixels_to_trim = img.width() - width;
-    let edge_height_to_trim = pixels_to_trim / 2;
+    let edge_height_to_trim = crop_offset(pixels_to_trim, img.width(), width, focal_x);
     img.crop(edge_height_to_trim, 0, width, height)
   }
 }
 
-pub fn process_image(settings: &ImgprssrConfig, mut img: DynamicImage, params: crate::parameters::ImageParameters) -> DynamicImage {
+// Scale to fit inside the box, then pad out to the full box size
+fn fit_contain(img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling, background: image::Rgba<u8>) -> DynamicImage {
+  let fitted = fit_inside(img, width, height, scaling_filter, oversize_handling);
+  let mut canvas = image::RgbaImage::from_pixel(width, height, background);
+  let x = (width - fitted.width()) / 2;
+  let y = (height - fitted.height()) / 2;
+  image::imageops::overlay(&mut canvas, &fitted.to_rgba8(), x as i64, y as i64);
+  DynamicImage::ImageRgba8(canvas)
+}
+
+// Extends the canvas on each edge, filling with the background colour
+fn pad_image(img: DynamicImage, (top, right, bottom, left): (u32, u32, u32, u32), background: image::Rgba<u8>) -> DynamicImage {
+  let mut canvas = image::RgbaImage::from_pixel(img.width() + left + right, img.height() + top + bottom, background);
+  image::imageops::overlay(&mut canvas, &img.to_rgba8(), left as i64, top as i64);
+  DynamicImage::ImageRgba8(canvas)
+}
+
+// Stretch to the box, ignoring the source aspect ratio
+fn fit_fill(img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
+  if oversize_handling != OversizedImageHandling::Upscale {
+    let (nwidth, nheight) = (width.min(img.width()), height.min(img.height()));
+    return img.resize_exact(nwidth, nheight, scaling_filter);
+  }
+  img.resize_exact(width, height, scaling_filter)
+}
+
+// Scale so the whole image sits within the box
+fn fit_inside(img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
+  if oversize_handling != OversizedImageHandling::Upscale && img.width() <= width && img.height() <= height {
+    return img;
+  }
+  img.resize(width, height, scaling_filter)
+}
+
+// Scale so the image covers the box, without cropping the excess
+fn fit_outside(img: DynamicImage, width: u32, height: u32, scaling_filter: image::imageops::FilterType, oversize_handling: OversizedImageHandling) -> DynamicImage {
+  let ratio = f64::max(width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+  if oversize_handling != OversizedImageHandling::Upscale && ratio > 1.0 {
+    return img;
+  }
+  let nwidth = ((img.width() as f64 * ratio).round() as u32).max(width);
+  let nheight = ((img.height() as f64 * ratio).round() as u32).max(height);
+  img.resize_exact(nwidth, nheight, scaling_filter)
+}
+
+pub fn oversize_handling(settings: &ImgprssrConfig, params: &ImageParameters) -> OversizedImageHandling {
+  params.oversized_handling.unwrap_or(settings.default_oversize_handling)
+}
+
+
+// The crop rectangle in source pixels, if it lies within the image
+pub fn crop_bounds(img: &DynamicImage, crop: CropRegion) -> Option<(u32, u32, u32, u32)> {
+  let (x, y, width, height) = match crop {
+    CropRegion::Pixels(x, y, width, height) => (x, y, width, height),
+    CropRegion::Fractions(x, y, width, height) => (
+      (x * img.width() as f32).round() as u32,
+      (y * img.height() as f32).round() as u32,
+      (width * img.width() as f32).round() as u32,
+      (height * img.height() as f32).round() as u32
+    ),
+  };
+  let fits_width = x.checked_add(width).map_or(false, |right| right <= img.width());
+  let fits_height = y.checked_add(height).map_or(false, |bottom| bottom <= img.height());
+  if width == 0 || height == 0 || !fits_width || !fits_height {
+    return None;
+  }
+  Some((x, y, width, height))
+}
+
+// Whether every channel is within the tolerance, treating any two fully
+// transparent pixels as equal
+fn matches_colour(pixel: &image::Rgba<u8>, colour: &image::Rgba<u8>, tolerance: u8) -> bool {
+  (pixel[3] == 0 && colour[3] == 0) || pixel.0.iter().zip(colour.0.iter()).all(|(a, b)| a.abs_diff(*b) <= tolerance)
+}
+
+// The bounds within a width by height region left after removing uniform
+// edges matching the colour, or None if there is nothing to trim or
+// nothing would be left
+fn trim_region(width: u32, height: u32, pixel: impl Fn(u32, u32) -> image::Rgba<u8>, colour: image::Rgba<u8>, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+  let row_matches = |y: u32| (0..width).all(|x| matches_colour(&pixel(x, y), &colour, tolerance));
+  let top = (0..height).find(|y| !row_matches(*y))?;
+  let bottom = (0..height).rev().find(|y| !row_matches(*y))?;
+  let column_matches = |x: u32| (top..=bottom).all(|y| matches_colour(&pixel(x, y), &colour, tolerance));
+  let left = (0..width).find(|x| !column_matches(*x))?;
+  let right = (0..width).rev().find(|x| !column_matches(*x))?;
+  let (trimmed_width, trimmed_height) = (right - left + 1, bottom - top + 1);
+  if (trimmed_width, trimmed_height) == (width, height) {
+    return None;
+  }
+  Some((left, top, trimmed_width, trimmed_height))
+}
+
+// The bounds left after removing uniform edges matching the top left pixel,
+// or None if there is nothing to trim or nothing would be left
+pub fn trim_bounds(img: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+  trim_region(img.width(), img.height(), |x, y| img.get_pixel(x, y), img.get_pixel(0, 0), tolerance)
+}
+
+// Which corner of the source, as (right, bottom), ends up top left
+// once rotated then flipped
+fn top_left_source_corner(rotate: Option<Rotation>, flip: Option<Flip>) -> (bool, bool) {
+  let (right, bottom) = match flip {
+    Some(Flip::Horizontal) => (true, false),
+    Some(Flip::Vertical) => (false, true),
+    Some(Flip::Both) => (true, true),
+    None => (false, false),
+  };
+  match rotate {
+    Some(Rotation::Rotate90) => (bottom, !right),
+    Some(Rotation::Rotate180) => (!right, !bottom),
+    Some(Rotation::Rotate270) => (!bottom, right),
+    None => (right, bottom),
+  }
+}
+
+// Turns the image upright according to an EXIF Orientation tag value
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+  match orientation {
+    2 => img.fliph(),
+    3 => img.rotate180(),
+    4 => img.flipv(),
+    5 => img.rotate90().fliph(),
+    6 => img.rotate90(),
+    7 => img.rotate270().fliph(),
+    8 => img.rotate270(),
+    _ => img,
+  }
+}
+
+fn rotate_and_flip(mut img: DynamicImage, rotate: Option<Rotation>, flip: Option<Flip>) -> DynamicImage {
+  img = match rotate {
+    Some(Rotation::Rotate90) => img.rotate90(),
+    Some(Rotation::Rotate180) => img.rotate180(),
+    Some(Rotation::Rotate270) => img.rotate270(),
+    None => img,
+  };
+  match flip {
+    Some(Flip::Horizontal) => img.fliph(),
+    Some(Flip::Vertical) => img.flipv(),
+    Some(Flip::Both) => img.fliph().flipv(),
+    None => img,
+  }
+}
+
+// Scales the red, green and blue channels' distance from their luminance,
+// leaving any alpha alone. Works in whatever range the channels hold
+fn saturate_channels(channels: &mut [f32], factor: f32, max: f32) {
+  let luminance = 0.299 * channels[0] + 0.587 * channels[1] + 0.114 * channels[2];
+  for channel in channels.iter_mut().take(3) {
+    *channel = (luminance + (*channel - luminance) * factor).clamp(0.0, max);
+  }
+}
+
+// Keeps the image's own colour type, like the other adjustments do
+fn saturate(img: DynamicImage, factor: f32) -> DynamicImage {
+  let adjust_u8 = |pixel: &mut [u8]| {
+    let mut channels = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+    saturate_channels(&mut channels, factor, 255.0);
+    for (channel, adjusted) in pixel.iter_mut().zip(channels) {
+      *channel = adjusted.round() as u8;
+    }
+  };
+  match img {
+    DynamicImage::ImageRgb8(mut buffer) => {
+      buffer.pixels_mut().for_each(|pixel| adjust_u8(&mut pixel.0));
+      DynamicImage::ImageRgb8(buffer)
+    },
+    DynamicImage::ImageRgba8(mut buffer) => {
+      buffer.pixels_mut().for_each(|pixel| adjust_u8(&mut pixel.0));
+      DynamicImage::ImageRgba8(buffer)
+    },
+    // Greys have no colour to scale
+    img if !img.color().has_color() => img,
+    // Deeper images go through floats so they keep their precision
+    img => {
+      let colour = img.color();
+      let mut buffer = img.into_rgba32f();
+      buffer.pixels_mut().for_each(|pixel| saturate_channels(&mut pixel.0, factor, 1.0));
+      let saturated = DynamicImage::ImageRgba32F(buffer);
+      match colour {
+        image::ColorType::Rgb16 => DynamicImage::ImageRgb16(saturated.into_rgb16()),
+        image::ColorType::Rgba16 => DynamicImage::ImageRgba16(saturated.into_rgba16()),
+        image::ColorType::Rgb32F => DynamicImage::ImageRgb32F(saturated.into_rgb32f()),
+        _ => saturated,
+      }
+    },
+  }
+}
+
+fn adjust_colours(mut img: DynamicImage, params: &ImageParameters) -> DynamicImage {
+  if let Some(brightness) = params.brightness {
+    img = img.brighten(brightness);
+  }
+  if let Some(contrast) = params.contrast {
+    img = img.adjust_contrast(contrast);
+  }
+  if let Some(hue) = params.hue {
+    img = img.huerotate(hue);
+  }
+  if let Some(saturation) = params.saturation {
+    img = saturate(img, saturation);
+  }
+  if params.grayscale {
+    img = img.grayscale();
+  }
+  if params.invert {
+    img.invert();
+  }
+  img
+}
+
+// Scales the watermark relative to the image width, fades it to
+// the configured opacity and blends it in at the configured position
+fn apply_watermark(mut img: DynamicImage, watermark: &DynamicImage, settings: &ImgprssrConfig) -> DynamicImage {
+  if img.width().max(img.height()) < settings.watermark_min_size {
+    return img;
+  }
+  let margin = settings.watermark_margin;
+  let max_width = img.width().saturating_sub(margin * 2);
+  let max_height = img.height().saturating_sub(margin * 2);
+  let target_width = ((img.width() as f32 * settings.watermark_scale).round() as u32).min(max_width);
+  if target_width == 0 || max_height == 0 {
+    return img;
+  }
+  let mut overlay = watermark.resize(target_width, max_height, image::imageops::FilterType::Triangle).to_rgba8();
+  for pixel in overlay.pixels_mut() {
+    pixel[3] = (pixel[3] as f32 * settings.watermark_opacity).round() as u8;
+  }
+  let (focal_x, focal_y) = settings.watermark_position.focal_point();
+  let x = margin + ((max_width - overlay.width()) as f32 * focal_x).round() as u32;
+  let y = margin + ((max_height - overlay.height()) as f32 * focal_y).round() as u32;
+  image::imageops::overlay(&mut img, &overlay, x as i64, y as i64);
+  img
+}
+
+// Requested width and height in device pixels, scaled by the
+// device pixel ratio up to the configured maximum
+pub fn target_size(settings: &ImgprssrConfig, params: &ImageParameters) -> (Option<u32>, Option<u32>) {
+  let dpr = params.dpr.unwrap_or(1.0).min(settings.max_dpr);
+  let scale = |length: u32| ((length as f32 * dpr).round() as u32).max(1);
+  (params.width.map(scale), params.height.map(scale))
+}
+
+// Size of the image once cropped, rotated and trimmed, as process_image
+// will leave it, worked out without copying any pixels
+fn source_size(img: &DynamicImage, params: &ImageParameters) -> (u32, u32) {
+  let (x, y, width, height) = params.crop.and_then(|crop| crop_bounds(img, crop)).unwrap_or((0, 0, img.width(), img.height()));
+  let (width, height) = match params.trim {
+    // Trim runs after rotating and flipping, which only move the corner
+    // it takes its colour from, so the bounds can come from the crop as is
+    Some(tolerance) => {
+      let (right, bottom) = top_left_source_corner(params.rotate, params.flip);
+      let colour = img.get_pixel(x + if right { width - 1 } else { 0 }, y + if bottom { height - 1 } else { 0 });
+      trim_region(width, height, |px, py| img.get_pixel(x + px, y + py), colour, tolerance)
+        .map_or((width, height), |(_, _, width, height)| (width, height))
+    },
+    None => (width, height),
+  };
+  if matches!(params.rotate, Some(Rotation::Rotate90 | Rotation::Rotate270)) {
+    return (height, width);
+  }
+  (width, height)
+}
+
+// Whether the parameters ask for more pixels than the (cropped) source has in either dimension
+pub fn exceeds_source(settings: &ImgprssrConfig, img: &DynamicImage, params: &ImageParameters) -> bool {
+  exceeds_size(settings, source_size(img, params), params)
+}
+
+fn exceeds_size(settings: &ImgprssrConfig, (source_width, source_height): (u32, u32), params: &ImageParameters) -> bool {
+  let (target_width, target_height) = target_size(settings, params);
+  target_width.map_or(false, |width| width > source_width) || target_height.map_or(false, |height| height > source_height)
+}
+
+// The size process_image will produce, worked out without doing any
+// of the work so oversized output can be refused up front
+pub fn output_size(settings: &ImgprssrConfig, img: &DynamicImage, params: &ImageParameters) -> (u32, u32) {
+  output_size_from(settings, source_size(img, params), params)
+}
+
+fn output_size_from(settings: &ImgprssrConfig, (source_width, source_height): (u32, u32), params: &ImageParameters) -> (u32, u32) {
+  let upscale = oversize_handling(settings, params) == OversizedImageHandling::Upscale;
+  let scaled = |ratio: f64| ((source_width as f64 * ratio).round() as u32, (source_height as f64 * ratio).round() as u32);
+  let (width, height) = match target_size(settings, params) {
+    (Some(width), None) if upscale || source_width > width => (width, scaled(width as f64 / source_width as f64).1),
+    (None, Some(height)) if upscale || source_height > height => (scaled(height as f64 / source_height as f64).0, height),
+    (Some(width), Some(height)) => match params.fit.unwrap_or(FitMode::Cover) {
+      FitMode::Cover if !upscale && (source_width < width || source_height < height) => (source_width, source_height),
+      FitMode::Cover | FitMode::Contain => (width, height),
+      FitMode::Fill if upscale => (width, height),
+      FitMode::Fill => (width.min(source_width), height.min(source_height)),
+      FitMode::Inside if !upscale && source_width <= width && source_height <= height => (source_width, source_height),
+      FitMode::Inside => scaled(f64::min(width as f64 / source_width as f64, height as f64 / source_height as f64)),
+      FitMode::Outside => {
+        let ratio = f64::max(width as f64 / source_width as f64, height as f64 / source_height as f64);
+        if !upscale && ratio > 1.0 {
+          (source_width, source_height)
+        } else {
+          let (scaled_width, scaled_height) = scaled(ratio);
+          (scaled_width.max(width), scaled_height.max(height))
+        }
+      },
+    },
+    _ => (source_width, source_height),
+  };
+  match params.pad {
+    Some((top, right, bottom, left)) => (width.saturating_add(left).saturating_add(right), height.saturating_add(top).saturating_add(bottom)),
+    None => (width, height),
+  }
+}
+
+// Whether the request can be met from this source image, before doing any of the work
+pub fn check_request(settings: &ImgprssrConfig, img: &DynamicImage, params: &ImageParameters) -> Result<(), ImgprssrError> {
+  // Trimming scans the image, so only work this out the once
+  let source = source_size(img, params);
+  if oversize_handling(settings, params) == OversizedImageHandling::Reject && exceeds_size(settings, source, params) {
+    return Err(ImgprssrError::SourceTooSmall(img.width(), img.height()));
+  }
+  if params.crop.map_or(false, |crop| crop_bounds(img, crop).is_none()) {
+    return Err(ImgprssrError::CropOutOfBounds(img.width(), img.height()));
+  }
+  let (width, height) = output_size_from(settings, source, params);
+  if width > settings.max_output_width || height > settings.max_output_height {
+    return Err(ImgprssrError::OutputTooLarge);
+  }
+  // Crops, trims and fits only known once the source is here can still land
+  // between the allowed sizes, whatever the query asked for
+  let allowed = |sizes: &Vec<u32>, size| sizes.is_empty() || sizes.contains(&size);
+  if !allowed(&settings.allowed_widths, width) || !allowed(&settings.allowed_heights, height) {
+    return Err(ImgprssrError::OutputSizeNotAllowed(width, height));
+  }
+  Ok(())
+}
+
+pub fn process_image(settings: &ImgprssrConfig, mut img: DynamicImage, params: crate::parameters::ImageParameters, watermark: Option<DynamicImage>) -> DynamicImage {
+  if let Some((x, y, width, height)) = params.crop.and_then(|crop| crop_bounds(&img, crop)) {
+    img = img.crop_imm(x, y, width, height);
+  }
+  img = rotate_and_flip(img, params.rotate, params.flip);
+  if let Some((x, y, width, height)) = params.trim.and_then(|tolerance| trim_bounds(&img, tolerance)) {
+    img = img.crop_imm(x, y, width, height);
+  }
   let scaling_filter = if let Some(flt) = params.scaling_filter { flt } else { settings.default_filter };
-  let oversize_handling = if let Some(os) = params.oversized_handling { os } else { settings.default_oversize_handling };
-  if params.width.is_some() && params.height.is_none() {
-    img = resize_fit_width(img, params.width.unwrap(), scaling_filter, oversize_handling);
+  let oversize_handling = oversize_handling(settings, &params);
+  let focal_point = match (params.focal_point, params.gravity, params.width, params.height) {
+    (Some(focal_point), _, _, _) => focal_point,
+    (None, Some(Gravity::Smart), Some(width), Some(height)) => smart_focal_point(&img, width, height),
+    (None, gravity, _, _) => gravity.unwrap_or(Gravity::Centre).focal_point(),
+  };
+  let background = params.background.unwrap_or(image::Rgba([0, 0, 0, 0]));
+  img = match target_size(settings, &params) {
+    (Some(width), None) => resize_fit_width(img, width, scaling_filter, oversize_handling),
+    (None, Some(height)) => resize_fit_height(img, height, scaling_filter, oversize_handling),
+    (Some(width), Some(height)) => match params.fit.unwrap_or(FitMode::Cover) {
+      FitMode::Cover => fit_to_set_size(img, width, height, scaling_filter, oversize_handling, focal_point),
+      FitMode::Contain => fit_contain(img, width, height, scaling_filter, oversize_handling, background),
+      FitMode::Fill => fit_fill(img, width, height, scaling_filter, oversize_handling),
+      FitMode::Inside => fit_inside(img, width, height, scaling_filter, oversize_handling),
+      FitMode::Outside => fit_outside(img, width, height, scaling_filter, oversize_handling),
+    },
+    (None, None) => img,
+  };
+  img = adjust_colours(img, &params);
+  if let Some(sigma) = params.blur {
+    img = img.blur(sigma);
+  }
+  if let Some((sigma, threshold)) = params.sharpen {
+    img = img.unsharpen(sigma, threshold);
   }
-  if params.height.is_some() && params.width.is_none() {
-    img = resize_fit_height(img, params.height.unwrap(), scaling_filter, oversize_handling);
+  if let Some(watermark) = watermark {
+    img = apply_watermark(img, &watermark, settings);
   }
-  if params.height.is_some() && params.width.is_some() {
-    img = fit_to_set_size(img, params.width.unwrap(), params.height.unwrap(), scaling_filter, oversize_handling);
+  if let Some(pad) = params.pad {
+    img = pad_image(img, pad, background);
   }
   img
 }
@@ -75,8 +567,6 @@ pub fn process_image(settings: &ImgprssrConfig, mut img: DynamicImage, params: c
 mod tests {
   use std::path::Path;
 
-  use crate::parameters::{ImageParameters};
-
   use super::*;
 
   const TEST_IMAGE_PATH: &str = "./images/test_card_sml.png";
@@ -84,9 +574,9 @@ mod tests {
   #[test]
   fn no_params_doesnt_manipulate_image() {
     let img = image::open(Path::new(TEST_IMAGE_PATH)).unwrap();
-    let params = ImageParameters { width: None, height: None, scaling_filter: None, oversized_handling: None };
+    let params = ImageParameters { width: None, height: None, scaling_filter: None, oversized_handling: None, ..Default::default() };
     let cloned_image = img.clone();
-    assert_eq!(process_image(&ImgprssrConfig::default(), img, params), cloned_image);
+    assert_eq!(process_image(&ImgprssrConfig::default(), img, params, None), cloned_image);
   }
 
 
@@ -96,8 +586,8 @@ mod tests {
     let cases = [[123_u32, 61], [200_u32, 100], [300_u32, 150], [600_u32, 300]];
     for case in cases {
       let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
-      let params = ImageParameters { width: Some(case[0]), height: None, scaling_filter: None, oversized_handling: None };
-      let processed = process_image(&ImgprssrConfig::default(), img, params);
+      let params = ImageParameters { width: Some(case[0]), height: None, scaling_filter: None, oversized_handling: None, ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
       assert_eq!(processed.width(), case[0]);
       assert_eq!(processed.height(), case[1]);
     }
@@ -109,8 +599,8 @@ mod tests {
     let cases = [[236, 123], [400, 400], [250, 300]];
     for [width, height] in cases {
       let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
-      let params = ImageParameters { width: Some(width), height: Some(height), scaling_filter: None, oversized_handling: None };
-      let processed = process_image(&ImgprssrConfig::default(), img, params);
+      let params = ImageParameters { width: Some(width), height: Some(height), scaling_filter: None, oversized_handling: None, ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
       assert_eq!(processed.width(), width);
       assert_eq!(processed.height(), height);
     }
@@ -123,10 +613,734 @@ mod tests {
     let cases = [[123_u32, 246], [200_u32, 400], [150_u32, 300], [300_u32, 600]];
     for case in cases {
       let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
-      let params = ImageParameters { width: None, height: Some(case[0]), scaling_filter: None, oversized_handling: None };
-      let processed = process_image(&ImgprssrConfig::default(), img, params);
+      let params = ImageParameters { width: None, height: Some(case[0]), scaling_filter: None, oversized_handling: None, ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
       assert_eq!(processed.height(), case[0]);
       assert_eq!(processed.width(), case[1]);
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn fit_contain_letterboxes_to_absolute_size() {
+    let source_size = [1200_u32, 600];
+    let cases = [[400_u32, 400, 400, 200], [300, 300, 300, 150], [1000, 100, 200, 100]];
+    for [width, height, inner_width, inner_height] in cases {
+      let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(source_size[0], source_size[1], image::Rgb([255, 255, 255])));
+      let params = ImageParameters { width: Some(width), height: Some(height), fit: Some(FitMode::Contain), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None).to_rgba8();
+      assert_eq!(processed.dimensions(), (width, height));
+      let x_pad = (width - inner_width) / 2;
+      let y_pad = (height - inner_height) / 2;
+      assert_eq!(processed.get_pixel(width / 2, height / 2), &image::Rgba([255, 255, 255, 255]));
+      if y_pad > 0 {
+        assert_eq!(processed.get_pixel(width / 2, 0), &image::Rgba([0, 0, 0, 0]));
+        assert_eq!(processed.get_pixel(width / 2, y_pad), &image::Rgba([255, 255, 255, 255]));
+      }
+      if x_pad > 0 {
+        assert_eq!(processed.get_pixel(0, height / 2), &image::Rgba([0, 0, 0, 0]));
+        assert_eq!(processed.get_pixel(x_pad, height / 2), &image::Rgba([255, 255, 255, 255]));
+      }
+    }
+  }
+
+  #[test]
+  fn fit_contain_letterboxes_with_background() {
+    let img = solid_image([255, 0, 0]);
+    let params = ImageParameters { width: Some(100), height: Some(100), fit: Some(FitMode::Contain), background: Some(image::Rgba([255, 255, 255, 255])), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), img, params, None).to_rgba8();
+    assert_eq!(processed.dimensions(), (100, 100));
+    assert_eq!(processed.get_pixel(50, 0), &image::Rgba([255, 255, 255, 255]));
+    assert_eq!(processed.get_pixel(50, 50), &image::Rgba([255, 0, 0, 255]));
+  }
+
+  #[test]
+  fn applies_watermark_at_position() {
+    let settings = ImgprssrConfig {
+      watermark_position: Gravity::SouthEast,
+      watermark_margin: 5,
+      watermark_opacity: 1.0,
+      watermark_scale: 0.25,
+      ..Default::default()
+    };
+    let params = ImageParameters { width: Some(60), ..Default::default() };
+    let processed = process_image(&settings, solid_image([255, 0, 0]), params, Some(solid_image([0, 0, 255]))).to_rgb8();
+    // Resized to 60x30 first, then a 15x8 watermark 5px in from the bottom right
+    assert_eq!(processed.dimensions(), (60, 30));
+    assert_eq!(processed.get_pixel(54, 24), &image::Rgb([0, 0, 255]));
+    assert_eq!(processed.get_pixel(40, 17), &image::Rgb([0, 0, 255]));
+    assert_eq!(processed.get_pixel(55, 24), &image::Rgb([255, 0, 0]));
+    assert_eq!(processed.get_pixel(39, 24), &image::Rgb([255, 0, 0]));
+    assert_eq!(processed.get_pixel(54, 16), &image::Rgb([255, 0, 0]));
+  }
+
+  #[test]
+  fn watermark_respects_opacity_and_minimum_size() {
+    let mut settings = ImgprssrConfig {
+      watermark_position: Gravity::Centre,
+      watermark_opacity: 0.5,
+      ..Default::default()
+    };
+    let processed = process_image(&settings, solid_image([0, 0, 0]), ImageParameters::default(), Some(solid_image([255, 255, 255]))).to_rgb8();
+    assert_eq!(processed.get_pixel(60, 30), &image::Rgb([128, 128, 128]));
+    assert_eq!(processed.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+    settings.watermark_min_size = 121;
+    let processed = process_image(&settings, solid_image([0, 0, 0]), ImageParameters::default(), Some(solid_image([255, 255, 255]))).to_rgb8();
+    assert_eq!(processed.get_pixel(60, 30), &image::Rgb([0, 0, 0]));
+  }
+
+  #[test]
+  fn pads_canvas_with_background() {
+    let img = solid_image([255, 0, 0]);
+    let params = ImageParameters { width: Some(60), pad: Some((1, 2, 3, 4)), background: Some(image::Rgba([0, 0, 255, 255])), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), img, params, None).to_rgba8();
+    assert_eq!(processed.dimensions(), (66, 34));
+    assert_eq!(processed.get_pixel(4, 1), &image::Rgba([255, 0, 0, 255]));
+    assert_eq!(processed.get_pixel(63, 30), &image::Rgba([255, 0, 0, 255]));
+    assert_eq!(processed.get_pixel(3, 1), &image::Rgba([0, 0, 255, 255]));
+    assert_eq!(processed.get_pixel(4, 0), &image::Rgba([0, 0, 255, 255]));
+    assert_eq!(processed.get_pixel(64, 30), &image::Rgba([0, 0, 255, 255]));
+    assert_eq!(processed.get_pixel(63, 31), &image::Rgba([0, 0, 255, 255]));
+    // Transparent padding without a background
+    let params = ImageParameters { pad: Some((5, 5, 5, 5)), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), solid_image([255, 0, 0]), params, None).to_rgba8();
+    assert_eq!(processed.get_pixel(0, 0), &image::Rgba([0, 0, 0, 0]));
+  }
+
+  #[test]
+  fn flattens_alpha_onto_background_for_jpeg() {
+    let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 20, image::Rgba([255, 0, 0, 0])));
+    let params = ImageParameters { background: Some(image::Rgba([255, 255, 255, 255])), ..Default::default() };
+    let buffer = process_image_to_buffer(&ImgprssrConfig::default(), img, image::ImageFormat::Jpeg, params, None).unwrap();
+    let decoded = image::load_from_memory(&buffer).unwrap().to_rgb8();
+    assert!(decoded.pixels().all(|px| px.0.iter().all(|c| *c > 250)));
+    let flattened = flatten_for_format(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 128]))), image::ImageFormat::Jpeg, image::Rgba([0, 0, 255, 255]));
+    assert_eq!(flattened.to_rgb8().get_pixel(0, 0), &image::Rgb([128, 0, 127]));
+  }
+
+  #[test]
+  fn fit_fill_stretches_to_absolute_size() {
+    let source_size = [1200_u32, 600];
+    let cases = [[236, 123], [400, 400], [250, 300], [1000, 100]];
+    for [width, height] in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width: Some(width), height: Some(height), fit: Some(FitMode::Fill), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), width);
+      assert_eq!(processed.height(), height);
+    }
+  }
+
+  #[test]
+  fn fit_inside_keeps_aspect_within_box() {
+    let source_size = [1200_u32, 600];
+    let cases = [[400_u32, 400, 400, 200], [300, 300, 300, 150], [1000, 100, 200, 100], [1200, 600, 1200, 600]];
+    for [width, height, expected_width, expected_height] in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width: Some(width), height: Some(height), fit: Some(FitMode::Inside), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), expected_width);
+      assert_eq!(processed.height(), expected_height);
+    }
+  }
+
+  #[test]
+  fn fit_outside_keeps_aspect_covering_box() {
+    let source_size = [1200_u32, 600];
+    let cases = [[400_u32, 400, 800, 400], [300, 50, 300, 150], [100, 300, 600, 300]];
+    for [width, height, expected_width, expected_height] in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width: Some(width), height: Some(height), fit: Some(FitMode::Outside), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), expected_width);
+      assert_eq!(processed.height(), expected_height);
+    }
+  }
+
+  #[test]
+  fn fit_modes_clamp_oversized_requests() {
+    let source_size = [1200_u32, 600];
+    let cases = [
+      (FitMode::Fill, [2400_u32, 300], [1200_u32, 300]),
+      (FitMode::Inside, [2400, 1200], [1200, 600]),
+      (FitMode::Outside, [2400, 300], [1200, 600]),
+      (FitMode::Contain, [1600, 1600], [1600, 1600])
+    ];
+    for (fit, [width, height], [expected_width, expected_height]) in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width: Some(width), height: Some(height), fit: Some(fit), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), expected_width);
+      assert_eq!(processed.height(), expected_height);
+    }
+  }
+
+  // Left half red, right half blue - or top/bottom when tall
+  fn split_image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+      let first_half = if width > height { x < width / 2 } else { y < height / 2 };
+      if first_half { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+    }))
+  }
+
+  #[test]
+  fn gravity_positions_wide_crop() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let cases = [
+      (Gravity::West, [red, red]),
+      (Gravity::NorthWest, [red, red]),
+      (Gravity::SouthWest, [red, red]),
+      (Gravity::East, [blue, blue]),
+      (Gravity::NorthEast, [blue, blue]),
+      (Gravity::SouthEast, [blue, blue]),
+      (Gravity::Centre, [red, blue]),
+      (Gravity::North, [red, blue]),
+      (Gravity::South, [red, blue])
+    ];
+    for (gravity, [left, right]) in cases {
+      let params = ImageParameters { width: Some(300), height: Some(300), gravity: Some(gravity), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), split_image(1200, 600), params, None).to_rgb8();
+      assert_eq!(processed.dimensions(), (300, 300));
+      assert_eq!(processed.get_pixel(0, 150), &left);
+      assert_eq!(processed.get_pixel(299, 150), &right);
+    }
+  }
+
+  #[test]
+  fn gravity_positions_tall_crop() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let cases = [
+      (Gravity::North, [red, red]),
+      (Gravity::NorthEast, [red, red]),
+      (Gravity::South, [blue, blue]),
+      (Gravity::SouthWest, [blue, blue]),
+      (Gravity::Centre, [red, blue]),
+      (Gravity::East, [red, blue])
+    ];
+    for (gravity, [top, bottom]) in cases {
+      let params = ImageParameters { width: Some(300), height: Some(300), gravity: Some(gravity), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), split_image(600, 1200), params, None).to_rgb8();
+      assert_eq!(processed.dimensions(), (300, 300));
+      assert_eq!(processed.get_pixel(150, 0), &top);
+      assert_eq!(processed.get_pixel(150, 299), &bottom);
+    }
+  }
+
+  #[test]
+  fn focal_point_positions_crop() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let cases = [
+      ((0.1, 0.5), [red, red]),
+      ((0.3, 0.5), [red, red]),
+      ((0.5, 0.5), [red, blue]),
+      ((0.8, 0.5), [blue, blue])
+    ];
+    for (focal_point, [left, right]) in cases {
+      // The focal point wins over gravity
+      let params = ImageParameters { width: Some(200), height: Some(300), gravity: Some(Gravity::East), focal_point: Some(focal_point), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), split_image(1200, 600), params, None).to_rgb8();
+      assert_eq!(processed.dimensions(), (200, 300));
+      assert_eq!(processed.get_pixel(0, 150), &left);
+      assert_eq!(processed.get_pixel(199, 150), &right);
+    }
+  }
+
+  #[test]
+  fn crops_source_before_resizing() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let cases = [
+      (CropRegion::Pixels(0, 0, 600, 600), red),
+      (CropRegion::Pixels(600, 0, 600, 600), blue),
+      (CropRegion::Fractions(0.0, 0.0, 0.5, 1.0), red),
+      (CropRegion::Fractions(0.5, 0.0, 0.5, 1.0), blue)
+    ];
+    for (crop, colour) in cases {
+      let params = ImageParameters { width: Some(300), crop: Some(crop), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), split_image(1200, 600), params, None).to_rgb8();
+      assert_eq!(processed.dimensions(), (300, 300));
+      assert!(processed.pixels().all(|px| px == &colour));
+    }
+  }
+
+  #[test]
+  fn crop_bounds_rejects_rectangles_outside_image() {
+    let img = image::DynamicImage::new_rgb8(1200, 600);
+    let cases = [
+      (CropRegion::Pixels(0, 0, 1200, 600), Some((0, 0, 1200, 600))),
+      (CropRegion::Pixels(100, 50, 200, 100), Some((100, 50, 200, 100))),
+      (CropRegion::Fractions(0.25, 0.5, 0.5, 0.5), Some((300, 300, 600, 300))),
+      (CropRegion::Pixels(1100, 0, 200, 100), None),
+      (CropRegion::Pixels(0, 500, 100, 101), None),
+      (CropRegion::Pixels(0, 0, 0, 100), None),
+      (CropRegion::Pixels(u32::MAX, 0, 10, 10), None),
+      (CropRegion::Fractions(0.75, 0.0, 0.5, 0.5), None)
+    ];
+    for (crop, expected) in cases {
+      assert_eq!(crop_bounds(&img, crop), expected);
+    }
+  }
+
+  // Distinct colour in each corner so orientation can be checked
+  fn corner_image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+      match (x < width / 2, y < height / 2) {
+        (true, true) => image::Rgb([255, 0, 0]),
+        (false, true) => image::Rgb([0, 255, 0]),
+        (true, false) => image::Rgb([0, 0, 255]),
+        (false, false) => image::Rgb([255, 255, 255]),
+      }
+    }))
+  }
+
+  fn corners(img: &DynamicImage) -> [image::Rgb<u8>; 4] {
+    let img = img.to_rgb8();
+    let (right, bottom) = (img.width() - 1, img.height() - 1);
+    [*img.get_pixel(0, 0), *img.get_pixel(right, 0), *img.get_pixel(0, bottom), *img.get_pixel(right, bottom)]
+  }
+
+  #[test]
+  fn rotates_and_flips_before_resizing() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let green = image::Rgb([0_u8, 255, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let white = image::Rgb([255_u8, 255, 255]);
+    let cases = [
+      (Some(Rotation::Rotate90), None, [300_u32, 600], [blue, red, white, green]),
+      (Some(Rotation::Rotate180), None, [600, 300], [white, blue, green, red]),
+      (Some(Rotation::Rotate270), None, [300, 600], [green, white, red, blue]),
+      (None, Some(Flip::Horizontal), [600, 300], [green, red, white, blue]),
+      (None, Some(Flip::Vertical), [600, 300], [blue, white, red, green]),
+      (None, Some(Flip::Both), [600, 300], [white, blue, green, red]),
+      (Some(Rotation::Rotate90), Some(Flip::Horizontal), [300, 600], [red, blue, green, white])
+    ];
+    for (rotate, flip, [expected_width, expected_height], expected_corners) in cases {
+      let params = ImageParameters { height: Some(expected_height), rotate, flip, ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), corner_image(1200, 600), params, None);
+      assert_eq!((processed.width(), processed.height()), (expected_width, expected_height));
+      assert_eq!(corners(&processed), expected_corners);
+    }
+  }
+
+  #[test]
+  fn applies_exif_orientation() {
+    let red = image::Rgb([255_u8, 0, 0]);
+    let green = image::Rgb([0_u8, 255, 0]);
+    let blue = image::Rgb([0_u8, 0, 255]);
+    let white = image::Rgb([255_u8, 255, 255]);
+    let cases = [
+      (1, [red, green, blue, white]),
+      (2, [green, red, white, blue]),
+      (3, [white, blue, green, red]),
+      (4, [blue, white, red, green]),
+      (5, [red, blue, green, white]),
+      (6, [blue, red, white, green]),
+      (7, [white, green, blue, red]),
+      (8, [green, white, red, blue])
+    ];
+    for (orientation, expected_corners) in cases {
+      let oriented = apply_orientation(corner_image(40, 20), orientation);
+      assert_eq!(corners(&oriented), expected_corners);
+    }
+  }
+
+  // Hard vertical edge between dark and light grey
+  fn edge_image() -> DynamicImage {
+    DynamicImage::ImageLuma8(image::GrayImage::from_fn(120, 60, |x, _| {
+      if x < 60 { image::Luma([64]) } else { image::Luma([192]) }
+    }))
+  }
+
+  #[test]
+  fn blur_softens_after_resizing() {
+    let params = ImageParameters { width: Some(60), blur: Some(2.0), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), edge_image(), params, None).to_luma8();
+    assert_eq!(processed.dimensions(), (60, 30));
+    let left_of_edge = processed.get_pixel(29, 15)[0];
+    let right_of_edge = processed.get_pixel(30, 15)[0];
+    assert!(left_of_edge > 64 && left_of_edge < 128);
+    assert!(right_of_edge < 192 && right_of_edge > 128);
+  }
+
+  #[test]
+  fn sharpen_increases_edge_contrast() {
+    let params = ImageParameters { sharpen: Some((1.0, 0)), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), edge_image(), params, None).to_luma8();
+    assert!(processed.get_pixel(59, 30)[0] < 64);
+    assert!(processed.get_pixel(60, 30)[0] > 192);
+    assert_eq!(processed.get_pixel(0, 30)[0], 64);
+    // Threshold leaves differences below it alone
+    let params = ImageParameters { sharpen: Some((1.0, 255)), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), edge_image(), params, None);
+    assert_eq!(processed.to_luma8(), edge_image().to_luma8());
+  }
+
+  fn solid_image(colour: [u8; 3]) -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_pixel(120, 60, image::Rgb(colour)))
+  }
+
+  #[test]
+  fn adjusts_colours() {
+    let cases = [
+      (ImageParameters { brightness: Some(50), ..Default::default() }, [100, 150, 200], [150, 200, 250]),
+      (ImageParameters { brightness: Some(-120), ..Default::default() }, [100, 150, 200], [0, 30, 80]),
+      (ImageParameters { contrast: Some(-100.0), ..Default::default() }, [0, 255, 128], [127, 127, 127]),
+      (ImageParameters { hue: Some(180), ..Default::default() }, [200, 40, 40], [0, 108, 108]),
+      (ImageParameters { saturation: Some(0.0), ..Default::default() }, [255, 0, 0], [76, 76, 76]),
+      (ImageParameters { saturation: Some(1.0), ..Default::default() }, [200, 40, 40], [200, 40, 40]),
+      (ImageParameters { saturation: Some(2.0), ..Default::default() }, [150, 100, 100], [185, 85, 85]),
+      (ImageParameters { grayscale: true, ..Default::default() }, [255, 255, 255], [255, 255, 255]),
+      (ImageParameters { invert: true, ..Default::default() }, [0, 100, 255], [255, 155, 0]),
+      (ImageParameters { brightness: Some(100), invert: true, ..Default::default() }, [0, 100, 200], [155, 55, 0])
+    ];
+    for (params, source, expected) in cases {
+      let processed = process_image(&ImgprssrConfig::default(), solid_image(source), params, None).to_rgb8();
+      assert!(processed.pixels().all(|px| px.0 == expected), "{:?} became {:?}", source, processed.get_pixel(0, 0));
+    }
+  }
+
+  #[test]
+  fn saturation_keeps_colour_type() {
+    let params = || ImageParameters { saturation: Some(2.0), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), solid_image([150, 100, 100]), params(), None);
+    assert_eq!(processed.color(), image::ColorType::Rgb8);
+    assert_eq!(processed.to_rgb8().get_pixel(0, 0).0, [185, 85, 85]);
+    let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 4, image::Rgba([150, 100, 100, 40])));
+    let processed = process_image(&ImgprssrConfig::default(), rgba, params(), None);
+    assert_eq!(processed.as_rgba8().unwrap().get_pixel(0, 0).0, [185, 85, 85, 40]);
+    let deep = DynamicImage::ImageRgb16(solid_image([150, 100, 100]).into_rgb16());
+    let processed = process_image(&ImgprssrConfig::default(), deep, params(), None);
+    assert_eq!(processed.color(), image::ColorType::Rgb16);
+    assert_eq!(processed.to_rgb8().get_pixel(0, 0).0, [185, 85, 85]);
+    let grey = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(4, 4, image::Luma([90])));
+    assert_eq!(process_image(&ImgprssrConfig::default(), grey.clone(), params(), None), grey);
+  }
+
+  #[test]
+  fn grayscale_removes_colour() {
+    let params = ImageParameters { grayscale: true, ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), solid_image([200, 40, 40]), params, None).to_rgb8();
+    let [r, g, b] = processed.get_pixel(0, 0).0;
+    assert!(r == g && g == b);
+  }
+
+  #[test]
+  fn colour_adjustments_apply_after_resizing() {
+    let params = ImageParameters { width: Some(60), invert: true, ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), solid_image([10, 20, 30]), params, None).to_rgb8();
+    assert_eq!(processed.dimensions(), (60, 30));
+    assert!(processed.pixels().all(|px| px.0 == [245, 235, 225]));
+  }
+
+  // Flat grey with a busy checkerboard patch centred on (x, y)
+  fn detailed_image(width: u32, height: u32, x: u32, y: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |px, py| {
+      let in_patch = px.abs_diff(x) < 60 && py.abs_diff(y) < 60;
+      if in_patch && (px / 10 + py / 10) % 2 == 0 { image::Rgb([255, 255, 255]) } else if in_patch { image::Rgb([0, 0, 0]) } else { image::Rgb([128, 128, 128]) }
+    }))
+  }
+
+  #[test]
+  fn smart_gravity_keeps_detail_in_frame() {
+    let cases = [
+      detailed_image(1200, 600, 150, 300),
+      detailed_image(1200, 600, 1050, 300),
+      detailed_image(600, 1200, 300, 1050),
+      detailed_image(600, 1200, 300, 150)
+    ];
+    for img in cases {
+      let params = ImageParameters { width: Some(300), height: Some(300), gravity: Some(Gravity::Smart), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img.clone(), params, None).to_rgb8();
+      assert_eq!(processed.dimensions(), (300, 300));
+      assert!(processed.pixels().any(|px| px.0 == [255, 255, 255]));
+      // Whereas a centre crop loses the detail entirely
+      let params = ImageParameters { width: Some(300), height: Some(300), gravity: Some(Gravity::Centre), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None).to_rgb8();
+      assert!(processed.pixels().all(|px| px.0 == [128, 128, 128]));
+    }
+  }
+
+  #[test]
+  fn smart_gravity_centres_flat_images() {
+    let img = solid_image([128, 128, 128]);
+    assert_eq!(smart_focal_point(&img, 10, 10), (0.5, 0.5));
+    assert_eq!(smart_focal_point(&img, 100, 10), (0.5, 0.5));
+  }
+
+  #[test]
+  fn upscales_when_requested() {
+    let source_size = [1200_u32, 600];
+    let cases = [
+      ([Some(2400_u32), None], [2400_u32, 1200]),
+      ([None, Some(900)], [1800, 900]),
+      ([Some(1600), Some(1600)], [1600, 1600])
+    ];
+    for ([width, height], [expected_width, expected_height]) in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width, height, oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), expected_width);
+      assert_eq!(processed.height(), expected_height);
+    }
+  }
+
+  #[test]
+  fn clamps_oversized_requests() {
+    let source_size = [1200_u32, 600];
+    let cases = [[Some(2400_u32), None], [None, Some(900)], [Some(1600), Some(1600)]];
+    for [width, height] in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width, height, oversized_handling: Some(OversizedImageHandling::Clamp), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), source_size[0]);
+      assert_eq!(processed.height(), source_size[1]);
+    }
+  }
+
+  #[test]
+  fn detects_requests_exceeding_source() {
+    let img = image::DynamicImage::new_rgb8(1200, 600);
+    let cases = [
+      ([Some(1200_u32), Some(600_u32)], false),
+      ([Some(300), None], false),
+      ([Some(1201), None], true),
+      ([None, Some(601)], true),
+      ([Some(100), Some(700)], true)
+    ];
+    for ([width, height], expected) in cases {
+      let params = ImageParameters { width, height, ..Default::default() };
+      assert_eq!(exceeds_source(&ImgprssrConfig::default(), &img, &params), expected);
+    }
+    let params = ImageParameters { width: Some(700), crop: Some(CropRegion::Pixels(0, 0, 600, 600)), ..Default::default() };
+    assert!(exceeds_source(&ImgprssrConfig::default(), &img, &params));
+    let params = ImageParameters { width: Some(700), dpr: Some(2.0), ..Default::default() };
+    assert!(exceeds_source(&ImgprssrConfig::default(), &img, &params));
+    let params = ImageParameters { width: Some(61), trim: Some(0), ..Default::default() };
+    assert!(exceeds_source(&ImgprssrConfig::default(), &bordered_image([255, 255, 255, 255], [200, 30, 30, 255]), &params));
+  }
+
+  #[test]
+  fn dpr_multiplies_requested_size() {
+    let source_size = [1200_u32, 600];
+    let cases = [
+      (1.0_f32, [Some(300_u32), None], [300_u32, 150]),
+      (2.0, [Some(300), None], [600, 300]),
+      (1.5, [None, Some(100)], [300, 150]),
+      (3.0, [Some(100), Some(100)], [300, 300]),
+      // Clamped to the configured maximum of 3
+      (4.0, [Some(100), Some(100)], [300, 300]),
+      // Doesn't upscale past the source under the default clamp
+      (3.0, [Some(600), None], [1200, 600])
+    ];
+    for (dpr, [width, height], [expected_width, expected_height]) in cases {
+      let img = image::DynamicImage::new_rgb8(source_size[0], source_size[1]);
+      let params = ImageParameters { width, height, dpr: Some(dpr), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None);
+      assert_eq!(processed.width(), expected_width);
+      assert_eq!(processed.height(), expected_height);
+    }
+  }
+
+  #[test]
+  fn encodes_to_requested_format() {
+    let cases = [
+      image::ImageFormat::Png,
+      image::ImageFormat::Jpeg,
+      image::ImageFormat::WebP,
+      image::ImageFormat::Gif,
+      image::ImageFormat::Bmp,
+      image::ImageFormat::Tiff
+    ];
+    for format in cases {
+      let img = image::DynamicImage::new_rgba8(120, 60);
+      let buffer = process_image_to_buffer(&ImgprssrConfig::default(), img, format, ImageParameters::default(), None).unwrap();
+      assert_eq!(image::guess_format(&buffer).unwrap(), format);
+    }
+  }
+
+  #[test]
+  fn jpeg_quality_changes_output_size() {
+    let img = image::open(Path::new(TEST_IMAGE_PATH)).unwrap();
+    let low = process_image_to_buffer(&ImgprssrConfig::default(), img.clone(), image::ImageFormat::Jpeg, ImageParameters { quality: Some(10), ..Default::default() }, None).unwrap();
+    let high = process_image_to_buffer(&ImgprssrConfig::default(), img, image::ImageFormat::Jpeg, ImageParameters { quality: Some(95), ..Default::default() }, None).unwrap();
+    assert!(low.len() < high.len());
+  }
+
+  #[test]
+  fn webp_quality_is_lossy_unless_lossless_requested() {
+    let img = image::open(Path::new(TEST_IMAGE_PATH)).unwrap();
+    let lossy = process_image_to_buffer(&ImgprssrConfig::default(), img.clone(), image::ImageFormat::WebP, ImageParameters { quality: Some(50), ..Default::default() }, None).unwrap();
+    let lossless = process_image_to_buffer(&ImgprssrConfig::default(), img.clone(), image::ImageFormat::WebP, ImageParameters { quality: Some(50), lossless: Some(true), ..Default::default() }, None).unwrap();
+    assert!(lossy.len() < lossless.len());
+    let decoded = image::load_from_memory(&lossless).unwrap();
+    assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+  }
+
+  #[test]
+  fn default_quality_only_applies_to_lossy_webp() {
+    let img = image::open(Path::new(TEST_IMAGE_PATH)).unwrap();
+    let encode = |settings: &ImgprssrConfig| process_image_to_buffer(settings, img.clone(), image::ImageFormat::WebP, ImageParameters::default(), None).unwrap();
+    let lossless = encode(&ImgprssrConfig::default());
+    assert_eq!(encode(&ImgprssrConfig { default_quality: Some(10), ..Default::default() }), lossless);
+    let lossy = encode(&ImgprssrConfig { default_quality: Some(10), default_lossless: false, ..Default::default() });
+    assert!(lossy.len() < lossless.len());
+  }
+
+  #[test]
+  fn png_compression_is_applied() {
+    let img = image::open(Path::new(TEST_IMAGE_PATH)).unwrap();
+    let fast = process_image_to_buffer(&ImgprssrConfig::default(), img.clone(), image::ImageFormat::Png, ImageParameters { png_compression: Some(image::codecs::png::CompressionType::Fast), ..Default::default() }, None).unwrap();
+    let best = process_image_to_buffer(&ImgprssrConfig::default(), img, image::ImageFormat::Png, ImageParameters { png_compression: Some(image::codecs::png::CompressionType::Best), ..Default::default() }, None).unwrap();
+    assert!(best.len() < fast.len());
+  }
+
+  // A solid subject inside uneven borders of the given colour
+  fn bordered_image(border: [u8; 4], subject: [u8; 4]) -> DynamicImage {
+    DynamicImage::ImageRgba8(image::RgbaImage::from_fn(120, 60, |x, y| {
+      if (20..80).contains(&x) && (10..40).contains(&y) {
+        image::Rgba(subject)
+      } else {
+        image::Rgba(border)
+      }
+    }))
+  }
+
+  #[test]
+  fn trims_uniform_borders() {
+    let cases = [
+      (bordered_image([255, 255, 255, 255], [200, 30, 30, 255]), 0),
+      (bordered_image([0, 0, 0, 0], [200, 30, 30, 255]), 0),
+      (bordered_image([255, 0, 255, 0], [200, 30, 30, 255]), 0),
+      (bordered_image([250, 250, 250, 255], [240, 240, 240, 255]), 5)
+    ];
+    for (img, tolerance) in cases {
+      assert_eq!(trim_bounds(&img, tolerance), Some((20, 10, 60, 30)));
+      let params = ImageParameters { trim: Some(tolerance), ..Default::default() };
+      let processed = process_image(&ImgprssrConfig::default(), img, params, None).to_rgba8();
+      assert_eq!(processed.dimensions(), (60, 30));
+    }
+  }
+
+  #[test]
+  fn trim_tolerance_keeps_near_matches_within_the_subject() {
+    let img = bordered_image([250, 250, 250, 255], [240, 240, 240, 255]);
+    assert_eq!(trim_bounds(&img, 10), None);
+    assert_eq!(trim_bounds(&img, 9), Some((20, 10, 60, 30)));
+  }
+
+  #[test]
+  fn trim_leaves_images_without_borders() {
+    assert_eq!(trim_bounds(&corner_image(120, 60), 0), None);
+    assert_eq!(trim_bounds(&solid_image([255, 255, 255]), 0), None);
+    let params = ImageParameters { trim: Some(0), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), solid_image([255, 255, 255]), params, None);
+    assert_eq!((processed.width(), processed.height()), (120, 60));
+  }
+
+  #[test]
+  fn trims_before_resizing() {
+    let params = ImageParameters { width: Some(30), height: Some(30), trim: Some(0), ..Default::default() };
+    let processed = process_image(&ImgprssrConfig::default(), bordered_image([255, 255, 255, 255], [200, 30, 30, 255]), params, None).to_rgba8();
+    assert_eq!(processed.dimensions(), (30, 30));
+    assert!(processed.pixels().all(|px| px.0 == [200, 30, 30, 255]));
+  }
+
+  #[test]
+  fn output_size_matches_processed_image() {
+    let img = corner_image(120, 60);
+    let cases = [
+      ImageParameters::default(),
+      ImageParameters { width: Some(60), ..Default::default() },
+      ImageParameters { width: Some(240), ..Default::default() },
+      ImageParameters { width: Some(240), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { height: Some(45), ..Default::default() },
+      ImageParameters { height: Some(90), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(50), ..Default::default() },
+      ImageParameters { width: Some(200), height: Some(50), ..Default::default() },
+      ImageParameters { width: Some(200), height: Some(50), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(50), fit: Some(FitMode::Contain), ..Default::default() },
+      ImageParameters { width: Some(200), height: Some(200), fit: Some(FitMode::Contain), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(200), fit: Some(FitMode::Fill), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(200), fit: Some(FitMode::Fill), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(50), fit: Some(FitMode::Inside), ..Default::default() },
+      ImageParameters { width: Some(500), height: Some(500), fit: Some(FitMode::Inside), ..Default::default() },
+      ImageParameters { width: Some(500), height: Some(500), fit: Some(FitMode::Inside), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(50), fit: Some(FitMode::Outside), ..Default::default() },
+      ImageParameters { width: Some(50), height: Some(500), fit: Some(FitMode::Outside), oversized_handling: Some(OversizedImageHandling::Upscale), ..Default::default() },
+      ImageParameters { width: Some(30), crop: Some(CropRegion::Pixels(0, 0, 60, 60)), rotate: Some(Rotation::Rotate90), ..Default::default() },
+      ImageParameters { width: Some(40), dpr: Some(2.0), pad: Some((1, 2, 3, 4)), ..Default::default() }
+    ];
+    for params in cases {
+      let expected = output_size(&ImgprssrConfig::default(), &img, &params);
+      let processed = process_image(&ImgprssrConfig::default(), img.clone(), params, None);
+      assert_eq!((processed.width(), processed.height()), expected);
+    }
+
+    // Trim takes its colour from the top left corner after rotating,
+    // which here is white rather than the odd black pixel
+    let mut img = bordered_image([255, 255, 255, 255], [255, 0, 0, 255]);
+    img.as_mut_rgba8().unwrap().put_pixel(0, 0, image::Rgba([0, 0, 0, 255]));
+    let cases = [
+      ImageParameters { trim: Some(0), rotate: Some(Rotation::Rotate90), ..Default::default() },
+      ImageParameters { trim: Some(0), rotate: Some(Rotation::Rotate270), width: Some(20), ..Default::default() },
+      ImageParameters { trim: Some(0), flip: Some(Flip::Vertical), ..Default::default() },
+      ImageParameters { trim: Some(0), crop: Some(CropRegion::Pixels(0, 0, 70, 45)), rotate: Some(Rotation::Rotate90), ..Default::default() }
+    ];
+    for params in cases {
+      let expected = output_size(&ImgprssrConfig::default(), &img, &params);
+      let processed = process_image(&ImgprssrConfig::default(), img.clone(), params, None);
+      assert_eq!((processed.width(), processed.height()), expected);
+    }
+
+    // A stripe along the top then the left edge tells every corner apart,
+    // as each trims differently depending on which comes out top left
+    let mut top_stripe = bordered_image([255, 255, 255, 255], [255, 0, 0, 255]);
+    let mut left_stripe = top_stripe.clone();
+    for x in 0..120 {
+      top_stripe.as_mut_rgba8().unwrap().put_pixel(x, 0, image::Rgba([0, 0, 255, 255]));
+    }
+    for y in 0..60 {
+      left_stripe.as_mut_rgba8().unwrap().put_pixel(0, y, image::Rgba([0, 0, 255, 255]));
+    }
+    let rotations = [None, Some(Rotation::Rotate90), Some(Rotation::Rotate180), Some(Rotation::Rotate270)];
+    let flips = [None, Some(Flip::Horizontal), Some(Flip::Vertical), Some(Flip::Both)];
+    for img in [top_stripe, left_stripe] {
+      for rotate in rotations {
+        for flip in flips {
+          let params = ImageParameters { trim: Some(0), rotate, flip, crop: Some(CropRegion::Pixels(0, 0, 100, 50)), ..Default::default() };
+          let expected = output_size(&ImgprssrConfig::default(), &img, &params);
+          let processed = process_image(&ImgprssrConfig::default(), img.clone(), params, None);
+          assert_eq!((processed.width(), processed.height()), expected, "{rotate:?} {flip:?}");
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn check_request_refuses_unmeetable_requests() {
+    let img = corner_image(120, 60);
+    let cases = [
+      (ImgprssrConfig::default(), ImageParameters { width: Some(60), ..Default::default() }, Ok(())),
+      (ImgprssrConfig::default(), ImageParameters { crop: Some(CropRegion::Pixels(100, 0, 40, 40)), ..Default::default() }, Err(ImgprssrError::CropOutOfBounds(120, 60))),
+      (ImgprssrConfig::default(), ImageParameters { width: Some(121), oversized_handling: Some(OversizedImageHandling::Reject), ..Default::default() }, Err(ImgprssrError::SourceTooSmall(120, 60))),
+      (ImgprssrConfig { max_output_width: 100, ..Default::default() }, ImageParameters::default(), Err(ImgprssrError::OutputTooLarge)),
+      (ImgprssrConfig { max_output_width: 100, ..Default::default() }, ImageParameters { width: Some(100), ..Default::default() }, Ok(())),
+      (ImgprssrConfig { allowed_widths: vec![60], ..Default::default() }, ImageParameters { width: Some(60), ..Default::default() }, Ok(())),
+      (ImgprssrConfig { allowed_widths: vec![200], ..Default::default() }, ImageParameters { crop: Some(CropRegion::Pixels(0, 0, 23, 17)), ..Default::default() }, Err(ImgprssrError::OutputSizeNotAllowed(23, 17))),
+      (ImgprssrConfig { allowed_heights: vec![40], ..Default::default() }, ImageParameters { width: Some(60), ..Default::default() }, Err(ImgprssrError::OutputSizeNotAllowed(60, 30)))
+    ];
+    for (settings, params, expected) in cases {
+      assert_eq!(check_request(&settings, &img, &params), expected);
+    }
+  }
+
+  #[test]
+  fn encode_failures_are_errors() {
+    // ICO can't hold anything over 256 pixels across
+    let img = corner_image(300, 20);
+    let encoded = process_image_to_buffer(&ImgprssrConfig::default(), img, image::ImageFormat::Ico, ImageParameters::default(), None);
+    assert!(matches!(encoded, Err(ImgprssrError::Encode(_))));
+  }
+}
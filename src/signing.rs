@@ -0,0 +1,144 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum SignatureError {
+  MissingSignature,
+  InvalidSignature,
+  InvalidExpiry,
+  Expired
+}
+
+// The query's parameters in sorted order, without the signature itself,
+// so reordering parameters doesn't change what gets signed
+pub fn canonical_query(query: &str) -> String {
+  let mut parts: Vec<&str> = query.split('&')
+    .filter(|part| !part.is_empty() && *part != "sig" && !part.starts_with("sig="))
+    .collect();
+  parts.sort_unstable();
+  parts.join("&")
+}
+
+fn mac(key: &[u8], path: &str, query: &str) -> HmacSha256 {
+  // HMAC takes keys of any length, so this can't fail
+  let mut mac = HmacSha256::new_from_slice(key).unwrap();
+  mac.update(path.as_bytes());
+  mac.update(b"?");
+  mac.update(canonical_query(query).as_bytes());
+  mac
+}
+
+// Hex encoded HMAC-SHA256 of the path and canonical query
+pub fn signature(key: &[u8], path: &str, query: &str) -> String {
+  hex::encode(mac(key, path, query).finalize().into_bytes())
+}
+
+// The path and query with an "expires" (seconds since the unix epoch, if
+// given) and a "sig" parameter appended, ready to be requested
+pub fn sign_url(key: &[u8], path: &str, query: &str, expires: Option<u64>) -> String {
+  let mut query = query.to_owned();
+  if let Some(expires) = expires {
+    if !query.is_empty() {
+      query.push('&');
+    }
+    query.push_str(&format!("expires={expires}"));
+  }
+  let sig = signature(key, path, &query);
+  if query.is_empty() {
+    format!("{path}?sig={sig}")
+  } else {
+    format!("{path}?{query}&sig={sig}")
+  }
+}
+
+// Checks the "sig" parameter matches the rest of the request,
+// and that any "expires" is still in the future
+pub fn verify(key: &[u8], path: &str, query: &str, now: u64) -> Result<(), SignatureError> {
+  let param = |name: &str| query.split('&').find_map(|part| part.strip_prefix(name)?.strip_prefix('='));
+  let sig = param("sig").ok_or(SignatureError::MissingSignature)?;
+  let sig = hex::decode(sig).map_err(|_| SignatureError::InvalidSignature)?;
+  mac(key, path, query).verify_slice(&sig).map_err(|_| SignatureError::InvalidSignature)?;
+  if let Some(expires) = param("expires") {
+    let expires = expires.parse::<u64>().map_err(|_| SignatureError::InvalidExpiry)?;
+    if expires <= now {
+      return Err(SignatureError::Expired);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const KEY: &[u8] = b"not-a-very-good-secret";
+
+  #[test]
+  fn canonical_query_sorts_and_drops_signature() {
+    let cases = [
+      ("", ""),
+      ("width=100", "width=100"),
+      ("width=100&height=50", "height=50&width=100"),
+      ("sig=abc&width=100&height=50", "height=50&width=100"),
+      ("width=100&&sig", "width=100")
+    ];
+    for (query, expected) in cases {
+      assert_eq!(canonical_query(query), expected);
+    }
+  }
+
+  #[test]
+  fn signs_urls_that_verify() {
+    let cases = [
+      ("/test_card_sml.png", "", None),
+      ("/test_card_sml.png", "width=100&height=50", None),
+      ("/dir/test_card_sml.png", "width=100", Some(2000))
+    ];
+    for (path, query, expires) in cases {
+      let url = sign_url(KEY, path, query, expires);
+      let (signed_path, signed_query) = url.split_once('?').unwrap();
+      assert_eq!(signed_path, path);
+      assert_eq!(verify(KEY, signed_path, signed_query, 1000), Ok(()));
+    }
+  }
+
+  #[test]
+  fn parameter_order_doesnt_matter() {
+    let url = sign_url(KEY, "/test_card_sml.png", "width=100&height=50", None);
+    let sig = url.rsplit_once("sig=").unwrap().1;
+    assert_eq!(verify(KEY, "/test_card_sml.png", &format!("sig={sig}&height=50&width=100"), 0), Ok(()));
+  }
+
+  #[test]
+  fn rejects_tampered_requests() {
+    let url = sign_url(KEY, "/test_card_sml.png", "width=100", Some(2000));
+    let (_, query) = url.split_once('?').unwrap();
+    let sig = query.rsplit_once("sig=").unwrap().1;
+    let cases = [
+      ("/test_card_sml.png", "width=100&expires=2000".to_owned(), SignatureError::MissingSignature),
+      ("/test_card_sml.png", format!("width=100&expires=2000&sig={}", &sig[1..]), SignatureError::InvalidSignature),
+      ("/test_card_sml.png", "width=100&expires=2000&sig=zz".to_owned(), SignatureError::InvalidSignature),
+      ("/test_card_sml.png", format!("width=1000&expires=2000&sig={sig}"), SignatureError::InvalidSignature),
+      ("/test_card_sml.png", format!("width=100&expires=3000&sig={sig}"), SignatureError::InvalidSignature),
+      ("/test_card_sml.png", format!("width=100&sig={sig}"), SignatureError::InvalidSignature),
+      ("/other.png", query.to_owned(), SignatureError::InvalidSignature)
+    ];
+    for (path, query, err) in cases {
+      assert_eq!(verify(KEY, path, &query, 1000), Err(err));
+    }
+    assert_eq!(verify(b"another-key", "/test_card_sml.png", query, 1000), Err(SignatureError::InvalidSignature));
+  }
+
+  #[test]
+  fn rejects_expired_and_malformed_expiry() {
+    let url = sign_url(KEY, "/test_card_sml.png", "width=100", Some(2000));
+    let (_, query) = url.split_once('?').unwrap();
+    assert_eq!(verify(KEY, "/test_card_sml.png", query, 2000), Err(SignatureError::Expired));
+    let url = sign_url(KEY, "/test_card_sml.png", "expires=soon", None);
+    let (_, query) = url.split_once('?').unwrap();
+    assert_eq!(verify(KEY, "/test_card_sml.png", query, 0), Err(SignatureError::InvalidExpiry));
+  }
+}
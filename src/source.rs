@@ -1,103 +1,934 @@
-use std::{path::Path, str::FromStr};
-use image::{self, DynamicImage, ImageError, ImageFormat};
-use hyper::{Request, Body, Response, StatusCode, Client, Uri, client::{HttpConnector}};
+use std::{future::Future, io::Cursor, path::{Path, PathBuf}, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+use image::{self, DynamicImage, ImageFormat, io::{Limits, Reader as ImageReader}};
+use hyper::{Request, Response, Body, Client, Uri, HeaderMap, StatusCode, header, body::HttpBody, client::{HttpConnector, connect::Connect}};
 use hyper_tls::HttpsConnector;
-use imgprssr::{appconfig, parameters};
-
-async fn handle_response(response: Result<Response<Body>, hyper::Error>) -> Result<(DynamicImage, ImageFormat), ImageError> {
-    match response {
-        Ok(res) => {
-            let buf = hyper::body::to_bytes(res).await;
-            match buf {
-                Ok(bytes) => {
-                    let img = image::load_from_memory(&bytes);
-                    match img {
-                        Ok(img) => Ok((img, image::guess_format(&bytes).unwrap())),
-                        Err(err) => Err(err),
-                    }
-                },
-                Err(_) => Err(ImageError::IoError(std::io::Error::new(std::io::ErrorKind::Other ,"Failed to load image"))),
-            }
+use sha2::{Digest, Sha256};
+use imgprssr::{appconfig, error::ImgprssrError, parameters, process, signing};
+
+fn exif_orientation(bytes: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+}
+
+// Reads the dimensions from the image's headers first, so oversized
+// images are refused before anything gets allocated for them
+fn decode_image(bytes: &[u8], format: ImageFormat, settings: &appconfig::ImgprssrConfig) -> Result<DynamicImage, ImgprssrError> {
+    let (width, height) = ImageReader::with_format(Cursor::new(bytes), format).into_dimensions()?;
+    if width > settings.max_source_width || height > settings.max_source_height || width as u64 * height as u64 > settings.max_source_pixels {
+        return Err(ImgprssrError::SourceTooLarge);
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(settings.max_source_width);
+    limits.max_image_height = Some(settings.max_source_height);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let img = reader.decode()?;
+    match exif_orientation(bytes) {
+        Some(orientation) if settings.auto_orient => Ok(process::apply_orientation(img, orientation)),
+        _ => Ok(img),
+    }
+}
+
+// The upstream's caching headers, for our own response to build on
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct CacheHeaders {
+    pub cache_control: Option<String>,
+    pub last_modified: Option<String>,
+    pub etag: Option<String>
+}
+
+impl CacheHeaders {
+    fn from_headers(headers: &HeaderMap) -> CacheHeaders {
+        let value = |name| header_value(headers, name).map(|val| val.to_owned());
+        CacheHeaders {
+            cache_control: value("cache-control"),
+            last_modified: value("last-modified"),
+            etag: value("etag"),
+        }
+    }
+}
+
+// Where a redirect points, with a path-only Location resolved against
+// the URI that was redirected
+fn redirect_uri(current: &Uri, headers: &HeaderMap) -> Option<Uri> {
+    let location = Uri::from_str(header_value(headers, "location")?).ok()?;
+    if location.scheme().is_some() {
+        return Some(location);
+    }
+    let mut parts = location.into_parts();
+    parts.scheme = current.scheme().cloned();
+    parts.authority = current.authority().cloned();
+    Uri::from_parts(parts).ok()
+}
+
+// Reads an upstream body, refusing one that its Content-Length or the
+// bytes received so far put over the limit before it's all in memory
+async fn read_limited(res: Response<Body>, limit: u64) -> Result<Vec<u8>, ImgprssrError> {
+    let declared = header_value(res.headers(), "content-length").and_then(|val| val.parse::<u64>().ok());
+    if declared.map_or(false, |length| length > limit) {
+        return Err(ImgprssrError::SourceTooLarge);
+    }
+    let mut body = res.into_body();
+    let mut bytes = Vec::with_capacity(declared.unwrap_or(0) as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| ImgprssrError::UpstreamFailed)?;
+        if (bytes.len() + chunk.len()) as u64 > limit {
+            return Err(ImgprssrError::SourceTooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+// Follows redirects up to the configured limit, and only to the upstream's
+// own scheme and host unless cross host redirects are allowed, so an
+// upstream can't point us at anything else we can reach. Then passes on a
+// missing upstream image as missing and any other unsuccessful status as a failure
+async fn fetch_upstream<C>(client: &Client<C>, mut uri: Uri, settings: &appconfig::ImgprssrConfig) -> Result<(Vec<u8>, CacheHeaders), ImgprssrError>
+where C: Connect + Clone + Send + Sync + 'static {
+    let origin = (uri.scheme().cloned(), uri.authority().cloned());
+    for _ in 0..=settings.max_redirects {
+        let res = client.get(uri.clone()).await.map_err(|_| ImgprssrError::UpstreamFailed)?;
+        match res.status() {
+            status if status.is_success() => {
+                let cache_headers = CacheHeaders::from_headers(res.headers());
+                let bytes = read_limited(res, settings.max_source_bytes).await?;
+                return Ok((bytes, cache_headers));
+            },
+            status if status.is_redirection() => {
+                uri = redirect_uri(&uri, res.headers()).ok_or(ImgprssrError::UpstreamFailed)?;
+                if !settings.cross_host_redirects && (uri.scheme().cloned(), uri.authority().cloned()) != origin {
+                    return Err(ImgprssrError::UpstreamFailed);
+                }
+            },
+            StatusCode::NOT_FOUND | StatusCode::GONE => return Err(ImgprssrError::NotFound),
+            _ => return Err(ImgprssrError::UpstreamFailed),
+        }
+    }
+    Err(ImgprssrError::UpstreamFailed)
+}
+
+// Gives up on an upstream that takes longer than the configured timeout,
+// including the time to receive the body
+async fn with_timeout<T>(settings: &appconfig::ImgprssrConfig, fetch: impl Future<Output = Result<T, ImgprssrError>>) -> Result<T, ImgprssrError> {
+    tokio::time::timeout(Duration::from_secs(settings.upstream_timeout), fetch).await
+        .unwrap_or(Err(ImgprssrError::UpstreamTimedOut))
+}
+
+// A path that can't make a valid URI can't name an upstream image either
+fn upstream_uri(img_source: &str, target_path: &str) -> Result<Uri, ImgprssrError> {
+    Uri::from_str(&format!("{}{}", img_source, target_path)).map_err(|_| ImgprssrError::NotFound)
+}
+
+async fn source_image_from_upstream<C>(client: &Client<C>, img_source: &str, target_path: &str, settings: &appconfig::ImgprssrConfig) -> Result<(DynamicImage, ImageFormat, CacheHeaders), ImgprssrError>
+where C: Connect + Clone + Send + Sync + 'static {
+    let uri = upstream_uri(img_source, target_path)?;
+    with_timeout(settings, async {
+        let (bytes, cache_headers) = fetch_upstream(client, uri, settings).await?;
+        let format = image::guess_format(&bytes)?;
+        Ok((decode_image(&bytes, format, settings)?, format, cache_headers))
+    }).await
+}
+
+pub async fn source_image_from_http((client, img_source): &(Client<HttpConnector>, String), target_path: &str, settings: &appconfig::ImgprssrConfig) -> Result<(DynamicImage, ImageFormat, CacheHeaders), ImgprssrError> {
+    source_image_from_upstream(client, img_source, target_path, settings).await
+}
+
+pub async fn source_image_from_https((client, img_source): &(Client<HttpsConnector<HttpConnector>>, String), target_path: &str, settings: &appconfig::ImgprssrConfig) -> Result<(DynamicImage, ImageFormat, CacheHeaders), ImgprssrError> {
+    source_image_from_upstream(client, img_source, target_path, settings).await
+}
+
+// Decodes "%xx" escapes, None if they're malformed or not UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3).filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The requested path relative to the image root, or None if any segment,
+// once decoded, could climb out of it. Purely lexical, so nothing on
+// disk is touched for a hostile path
+fn relative_image_path(target_path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in target_path.split('/') {
+        let segment = percent_decode(segment)?;
+        match segment.as_str() {
+            "" | "." => {},
+            ".." => return None,
+            _ if segment.contains(['/', '\\', '\0']) => return None,
+            _ => relative.push(segment),
+        }
+    }
+    Some(relative)
+}
+
+// Whether any component below the root is a symlink
+fn passes_through_symlink(root: &Path, relative: &Path) -> bool {
+    let mut path = root.to_path_buf();
+    relative.components().any(|component| {
+        path.push(component);
+        std::fs::symlink_metadata(&path).map_or(false, |meta| meta.file_type().is_symlink())
+    })
+}
+
+// Where the requested image lives on disk, if it's allowed to be served
+fn resolve_file_path(img_source: &str, target_path: &str, policy: appconfig::SymlinkPolicy) -> Option<PathBuf> {
+    let relative = relative_image_path(target_path)?;
+    let root = Path::new(img_source);
+    match policy {
+        appconfig::SymlinkPolicy::Always => Some(root.join(relative)),
+        appconfig::SymlinkPolicy::Never if passes_through_symlink(root, &relative) => None,
+        _ => {
+            let root = root.canonicalize().ok()?;
+            let resolved = root.join(relative).canonicalize().ok()?;
+            resolved.starts_with(&root).then_some(resolved)
         },
-        Err(_) => Err(ImageError::IoError(std::io::Error::new(std::io::ErrorKind::Other ,"Failed to load image"))),
     }
 }
 
-pub async fn source_image_from_http((client, img_source): &(Client<HttpConnector>, String), target_path: &str) -> Result<(DynamicImage, ImageFormat), ImageError> {
-    let full_path = format!("{}{}", img_source, target_path);
-    handle_response(client.get(Uri::from_str(&full_path).unwrap()).await).await
+pub fn source_image_from_file(img_source: &str, target_path: &str, settings: &appconfig::ImgprssrConfig) -> Result<(DynamicImage, ImageFormat, CacheHeaders), ImgprssrError> {
+    let path = resolve_file_path(img_source, target_path, settings.follow_symlinks).ok_or(ImgprssrError::NotFound)?;
+    let metadata = std::fs::metadata(&path).map_err(|_| ImgprssrError::NotFound)?;
+    if metadata.len() > settings.max_source_bytes {
+        return Err(ImgprssrError::SourceTooLarge);
+    }
+    let bytes = std::fs::read(&path).map_err(|_| ImgprssrError::NotFound)?;
+    let format = ImageFormat::from_path(&path)?;
+    let cache_headers = CacheHeaders {
+        last_modified: metadata.modified().ok().map(httpdate::fmt_http_date),
+        ..Default::default()
+    };
+    Ok((decode_image(&bytes, format, settings)?, format, cache_headers))
 }
 
-pub async fn source_image_from_https((client, img_source): &(Client<HttpsConnector<HttpConnector>>, String), target_path: &str) -> Result<(DynamicImage, ImageFormat), ImageError> {
-    let full_path = format!("{}{}", img_source, target_path);
-    handle_response(client.get(Uri::from_str(&full_path).unwrap()).await).await
+pub const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width, Sec-CH-Viewport-Width";
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|val| val.to_str().ok()).map(|val| val.trim())
 }
 
-pub fn source_image_from_file(img_source: &str, target_path: &str) -> Result<(DynamicImage, ImageFormat), ImageError> {
-    let full_path = format!("{}{}", img_source, target_path);
-    let path = Path::new(&full_path);
-    match image::open(path) {
-        Ok(img) => Ok((img, image::ImageFormat::from_path(path).unwrap())),
-        Err(err) => Err(err),
+// Fills in missing dpr and width from client hint headers, returning the
+// names of every hint consulted. A hint that was missing still decided
+// the output, so it belongs in Vary as much as one that was used
+pub fn apply_client_hints(params: &mut parameters::ImageParameters, headers: &HeaderMap) -> Vec<&'static str> {
+    let mut consulted = vec![];
+    if params.dpr.is_none() {
+        consulted.push("Sec-CH-DPR");
+        if let Some(dpr) = header_value(headers, "sec-ch-dpr").and_then(|val| parameters::str_to_dpr(val).ok()) {
+            params.dpr = Some(dpr);
+        }
+    }
+    if params.width.is_none() {
+        consulted.push("Sec-CH-Width");
+        consulted.push("Sec-CH-Viewport-Width");
+        // Sec-CH-Width is already in device pixels, so undo the dpr
+        // that will be applied when processing
+        if let Some(width) = header_value(headers, "sec-ch-width").and_then(|val| val.parse::<u32>().ok()) {
+            let dpr = params.dpr.unwrap_or(1.0);
+            params.width = Some(((width as f32 / dpr).round() as u32).max(1));
+        } else if let Some(width) = header_value(headers, "sec-ch-viewport-width").and_then(|val| val.parse::<u32>().ok()) {
+            params.width = Some(width.max(1));
+        }
     }
+    consulted
 }
 
-pub async fn get_source_image(settings: &appconfig::ImgprssrConfig, req: Request<Body>) -> Result<(image::DynamicImage, image::ImageFormat, parameters::ImageParameters), Response<Body>> {
-  let target_path = req.uri().path();
-  let params_res: Result<parameters::ImageParameters, _> = req.uri().query().unwrap_or("").parse();
-  if params_res.is_err() {
-      return Err(Response::builder()
-          .status(StatusCode::BAD_REQUEST)
-          .body("Bad Request".into()).unwrap().into())
-  }
-  let params = params_res.unwrap();
-  let img_res = match &settings.image_source {
-    appconfig::ImgSource::Folder(fldr) => source_image_from_file(&fldr, target_path),
-    appconfig::ImgSource::Https(cfg) => source_image_from_https(cfg, target_path).await,
-    appconfig::ImgSource::Http(cfg) => source_image_from_http(cfg, target_path).await,
+const PRESET_PATH_PREFIX: &str = "/_p/";
+
+// Expands a named preset, from the "preset" parameter or a "/_p/<name>/"
+// path prefix, into the image path and query to use. The request's own
+// parameters come after the preset's so they override it.
+// None if the preset isn't configured
+pub fn apply_preset<'a>(settings: &appconfig::ImgprssrConfig, path: &'a str, query: &str) -> Option<(&'a str, String)> {
+  let (path_preset, image_path) = match path.strip_prefix(PRESET_PATH_PREFIX).and_then(|rest| rest.find('/').map(|slash| rest.split_at(slash))) {
+    Some((name, image_path)) => (Some(name), image_path),
+    None => (None, path),
   };
-  match img_res {
-      Ok((img, fmt)) => {
-          Ok((img, fmt, params))
-      },
-      Err(_) => {
-          Err(Response::builder()
-              .status(StatusCode::NOT_FOUND)
-              .body("Not Found".into()).unwrap().into())
-      }
+  let query_preset = query.split('&').find_map(|part| part.strip_prefix("preset="));
+  match query_preset.or(path_preset) {
+    Some(name) => {
+      let preset = settings.presets.get(name)?;
+      let expanded = if query.is_empty() { preset.to_owned() } else { format!("{preset}&{query}") };
+      Some((image_path, expanded))
+    },
+    None => Some((path, query.to_owned())),
+  }
+}
+
+async fn source_image(settings: &appconfig::ImgprssrConfig, target_path: &str) -> Result<(DynamicImage, ImageFormat, CacheHeaders), ImgprssrError> {
+  match &settings.image_source {
+    appconfig::ImgSource::Folder(fldr) => source_image_from_file(fldr, target_path, settings),
+    appconfig::ImgSource::Https(cfg) => source_image_from_https(cfg, target_path, settings).await,
+    appconfig::ImgSource::Http(cfg) => source_image_from_http(cfg, target_path, settings).await,
   }
 }
 
+// The watermark asked for by the request, otherwise the configured
+// one when the path falls under a watermarked prefix
+pub fn watermark_path<'a>(settings: &'a appconfig::ImgprssrConfig, params: &'a parameters::ImageParameters, target_path: &str) -> Option<&'a str> {
+  if let Some(requested) = &params.watermark {
+    return Some(requested);
+  }
+  match &settings.watermark_image {
+    Some(configured) if settings.watermark_path_prefixes.iter().any(|prefix| target_path.starts_with(prefix.as_str())) => Some(configured),
+    _ => None,
+  }
+}
+
+// Our output isn't the upstream's bytes, so its ETag only stands for
+// ours alongside everything that shapes the output
+fn derived_etag(upstream_etag: &str, params: &parameters::ImageParameters) -> String {
+  let digest = Sha256::digest(format!("{upstream_etag}{params:?}").as_bytes());
+  format!("W/\"{}\"", hex::encode(&digest[..16]))
+}
+
+// Everything a request needs before processing: the source image and its
+// format, the parameters to process it with, the headers the response
+// varies on, any watermark to stamp, and the upstream's caching headers
+#[derive(Debug)]
+pub struct SourcedImage {
+    pub image: DynamicImage,
+    pub format: ImageFormat,
+    pub params: parameters::ImageParameters,
+    pub vary: Vec<&'static str>,
+    pub watermark: Option<DynamicImage>,
+    pub cache_headers: CacheHeaders
+}
+
+pub async fn get_source_image(settings: &appconfig::ImgprssrConfig, req: Request<Body>) -> Result<SourcedImage, ImgprssrError> {
+  if let Some(key) = &settings.signing_key {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    signing::verify(key.as_bytes(), req.uri().path(), req.uri().query().unwrap_or(""), now).map_err(ImgprssrError::InvalidSignature)?;
+  }
+  let (target_path, query) = apply_preset(settings, req.uri().path(), req.uri().query().unwrap_or("")).ok_or(ImgprssrError::UnknownPreset)?;
+  let mut params = parameters::ImageParameters::from_query(&query, settings).map_err(ImgprssrError::InvalidParameters)?;
+  let mut vary = vec![];
+  if settings.negotiate_format {
+    vary.push("Accept");
+    if params.format.is_none() {
+      params.format = req.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .and_then(parameters::format_from_accept);
+    }
+  }
+  // Config refuses client hints alongside signing, but never let unsigned
+  // headers pick the size of a signed request however settings were built
+  if settings.client_hints && settings.signing_key.is_none() {
+    let unhinted = params.clone();
+    vary.append(&mut apply_client_hints(&mut params, req.headers()));
+    // Hints can move the size the query was already held to, and one the
+    // browser chose that isn't allowed is ignored rather than refused
+    if !params.hold_to_allowed_sizes(settings).is_empty() {
+      params = unhinted;
+    }
+  }
+  let (img, fmt, mut cache_headers) = source_image(settings, target_path).await?;
+  process::check_request(settings, &img, &params)?;
+  let watermark = match watermark_path(settings, &params, target_path) {
+      Some(path) => match source_image(settings, path).await {
+          Ok((watermark, _, _)) => Some(watermark),
+          // A forced watermark going missing is our problem, not the client's
+          Err(_) if params.watermark.is_none() => return Err(ImgprssrError::WatermarkUnavailable),
+          Err(_) => return Err(ImgprssrError::WatermarkNotFound),
+      },
+      None => None,
+  };
+  cache_headers.etag = cache_headers.etag.map(|etag| derived_etag(&etag, &params));
+  Ok(SourcedImage { image: img, format: fmt, params, vary, watermark, cache_headers })
+}
+
 #[cfg(test)]
 mod tests {
     use hyper::Client;
     use hyper_tls::HttpsConnector;
 
-    use crate::source::source_image_from_https;
+    use crate::source::{source_image_from_http, source_image_from_https};
 
-    use super::source_image_from_file;
+    use super::{source_image_from_file, CacheHeaders, apply_client_hints, apply_preset, decode_image, get_source_image, relative_image_path, watermark_path};
+    use imgprssr::appconfig::ImgprssrConfig;
+    use imgprssr::error::ImgprssrError;
+    use imgprssr::parameters::ImageParameterParseError;
+    use imgprssr::signing::SignatureError;
+    use hyper::HeaderMap;
+    use imgprssr::parameters::ImageParameters;
 
     // This is quite tied to some files - but honestly
     // might as well just test it this way
     #[test]
     fn works_with_local_file_paths() {
-        assert!(source_image_from_file("./images", "/test_card_sml.png").is_ok());
+        assert!(source_image_from_file("./images", "/test_card_sml.png", &ImgprssrConfig::default()).is_ok());
     }
     #[test]
     fn errors_with_local_file_paths() {
-        assert!(source_image_from_file("./images", "/this_image_doesnt_exist.png").is_err());
+        assert!(source_image_from_file("./images", "/this_image_doesnt_exist.png", &ImgprssrConfig::default()).is_err());
+    }
+    // Serves the test card along with a handful of ways an upstream can go wrong
+    async fn stub_upstream_response(req: hyper::Request<hyper::Body>) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+        let res = hyper::Response::builder();
+        let res = match req.uri().path() {
+            "/test_card_sml.png" => res
+                .header("cache-control", "public, max-age=60")
+                .header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+                .header("etag", "\"upstream\"")
+                .body(std::fs::read("./images/test_card_sml.png").unwrap().into()),
+            "/streamed.png" => {
+                let png = std::fs::read("./images/test_card_sml.png").unwrap();
+                let chunks: Vec<Result<Vec<u8>, std::io::Error>> = png.chunks(1024).map(|chunk| Ok(chunk.to_vec())).collect();
+                res.body(hyper::Body::wrap_stream(futures::stream::iter(chunks)))
+            },
+            "/gone.png" => res.status(410).body("Gone".into()),
+            "/broken.png" => res.status(500).body("Internal Server Error".into()),
+            "/unavailable.png" => res.status(503).body("Service Unavailable".into()),
+            "/forbidden.png" => res.status(403).body("Forbidden".into()),
+            "/moved.png" => res.status(301).header("location", "/test_card_sml.png").body(hyper::Body::empty()),
+            "/moved_twice.png" => res.status(302).header("location", "/moved.png").body(hyper::Body::empty()),
+            "/moved_elsewhere.png" => {
+                let host = req.headers()["host"].to_str().unwrap().to_owned();
+                res.status(307).header("location", format!("http://{host}/test_card_sml.png")).body(hyper::Body::empty())
+            },
+            "/moved_away.png" => {
+                let port = req.headers()["host"].to_str().unwrap().rsplit_once(':').unwrap().1.to_owned();
+                res.status(302).header("location", format!("http://localhost:{port}/test_card_sml.png")).body(hyper::Body::empty())
+            },
+            "/loop.png" => res.status(302).header("location", "/loop.png").body(hyper::Body::empty()),
+            "/nowhere.png" => res.status(302).body(hyper::Body::empty()),
+            _ => res.status(404).header("content-type", "text/html").body("<html><body>Not Found</body></html>".into()),
+        };
+        Ok(res.unwrap())
+    }
+
+    async fn stub_upstream() -> String {
+        let make_service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(stub_upstream_response))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{addr}")
     }
+
     #[tokio::test]
     async fn works_with_http_file_addresses() {
+        let upstream = stub_upstream().await;
         let https = HttpsConnector::new();
         let client = Client::builder().build::<_, hyper::Body>(https);
-        assert!(source_image_from_https(&(client, "https://raw.githubusercontent.com/LeeMartin77/imgprssr/main/images".to_owned()), "/test_card_sml.png").await.is_ok());
+        assert!(source_image_from_https(&(client, upstream.clone()), "/test_card_sml.png", &ImgprssrConfig::default()).await.is_ok());
+        assert!(source_image_from_http(&(Client::new(), upstream), "/test_card_sml.png", &ImgprssrConfig::default()).await.is_ok());
     }
     #[tokio::test]
     async fn errors_with_http_file_addresses() {
-
+        let upstream = stub_upstream().await;
         let https = HttpsConnector::new();
         let client = Client::builder().build::<_, hyper::Body>(https);
-        assert!(source_image_from_https(&(client, "https://raw.githubusercontent.com/LeeMartin77/imgprssr/main/images".to_owned()), "/this_image_doesnt_exist.png").await.is_err());
+        assert!(source_image_from_https(&(client, upstream), "/this_image_doesnt_exist.png", &ImgprssrConfig::default()).await.is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn passes_on_upstream_statuses() {
+        let source = (Client::new(), stub_upstream().await);
+        let cases = [
+            ("/this_image_doesnt_exist.png", ImgprssrError::NotFound),
+            ("/gone.png", ImgprssrError::NotFound),
+            ("/broken.png", ImgprssrError::UpstreamFailed),
+            ("/unavailable.png", ImgprssrError::UpstreamFailed),
+            ("/forbidden.png", ImgprssrError::UpstreamFailed),
+            ("/loop.png", ImgprssrError::UpstreamFailed),
+            ("/nowhere.png", ImgprssrError::UpstreamFailed)
+        ];
+        for (target_path, err) in cases {
+            assert_eq!(source_image_from_http(&source, target_path, &ImgprssrConfig::default()).await.err(), Some(err), "{target_path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_up_to_the_limit() {
+        let source = (Client::new(), stub_upstream().await);
+        let cases = [
+            ("/moved.png", 0, false),
+            ("/moved.png", 1, true),
+            ("/moved_elsewhere.png", 1, true),
+            ("/moved_twice.png", 1, false),
+            ("/moved_twice.png", 2, true),
+            ("/moved_away.png", 5, false)
+        ];
+        for (target_path, max_redirects, found) in cases {
+            let settings = ImgprssrConfig { max_redirects, ..Default::default() };
+            let sourced = source_image_from_http(&source, target_path, &settings).await;
+            match found {
+                true => assert!(sourced.is_ok(), "{target_path}"),
+                false => assert_eq!(sourced.err(), Some(ImgprssrError::UpstreamFailed), "{target_path}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_upstream_bodies_over_the_limit() {
+        let source = (Client::new(), stub_upstream().await);
+        let size = std::fs::metadata("./images/test_card_sml.png").unwrap().len();
+        for target_path in ["/test_card_sml.png", "/streamed.png"] {
+            let settings = ImgprssrConfig { max_source_bytes: size, ..Default::default() };
+            assert!(source_image_from_http(&source, target_path, &settings).await.is_ok(), "{target_path}");
+            let settings = ImgprssrConfig { max_source_bytes: size - 1, ..Default::default() };
+            assert_eq!(source_image_from_http(&source, target_path, &settings).await.err(), Some(ImgprssrError::SourceTooLarge), "{target_path}");
+        }
+        let settings = ImgprssrConfig { max_source_bytes: size - 1, ..Default::default() };
+        assert_eq!(source_image_from_file("./images", "/test_card_sml.png", &settings).err(), Some(ImgprssrError::SourceTooLarge));
+    }
+
+    #[tokio::test]
+    async fn follows_cross_host_redirects_only_when_allowed() {
+        let source = (Client::new(), stub_upstream().await);
+        let settings = ImgprssrConfig { cross_host_redirects: true, ..Default::default() };
+        assert!(source_image_from_http(&source, "/moved_away.png", &settings).await.is_ok());
+        let settings = ImgprssrConfig::default();
+        assert_eq!(source_image_from_http(&source, "/moved_away.png", &settings).await.err(), Some(ImgprssrError::UpstreamFailed));
+    }
+
+    #[tokio::test]
+    async fn keeps_upstream_cache_headers() {
+        let upstream = stub_upstream().await;
+        let settings = ImgprssrConfig { image_source: imgprssr::appconfig::ImgSource::Http((Client::new(), upstream)), ..Default::default() };
+        let cache_headers = get_source_image(&settings, hyper::Request::get("/moved.png?width=100").body(hyper::Body::empty()).unwrap()).await.unwrap().cache_headers;
+        assert_eq!(cache_headers.cache_control.as_deref(), Some("public, max-age=60"));
+        assert_eq!(cache_headers.last_modified.as_deref(), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        let etag = cache_headers.etag.unwrap();
+        assert!(etag.starts_with("W/\"") && etag != "\"upstream\"");
+
+        // The same upstream image processed differently is a different representation
+        let etag_for = |query: &'static str| {
+            let settings = &settings;
+            async move {
+                let req = hyper::Request::get(format!("/test_card_sml.png?{query}")).body(hyper::Body::empty()).unwrap();
+                get_source_image(settings, req).await.unwrap().cache_headers.etag.unwrap()
+            }
+        };
+        assert_eq!(etag_for("width=100").await, etag);
+        assert_eq!(etag_for("width=100&height=50").await, etag_for("height=50&width=100").await);
+        assert_ne!(etag_for("width=200").await, etag);
+
+        // Folder sources only know when the file last changed
+        let cache_headers = get_source_image(&ImgprssrConfig::default(), hyper::Request::get("/test_card_sml.png").body(hyper::Body::empty()).unwrap()).await.unwrap().cache_headers;
+        let modified = std::fs::metadata("./images/test_card_sml.png").unwrap().modified().unwrap();
+        assert_eq!(cache_headers, CacheHeaders { last_modified: Some(httpdate::fmt_http_date(modified)), ..Default::default() });
+    }
+
+    fn hint_headers(hints: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, val) in hints {
+            headers.insert(*name, val.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn client_hints_fill_missing_values() {
+        let cases = [
+            (vec![("sec-ch-dpr", "2")], (None, Some(2.0))),
+            (vec![("sec-ch-width", "600")], (Some(600), None)),
+            (vec![("sec-ch-dpr", "2"), ("sec-ch-width", "600")], (Some(300), Some(2.0))),
+            (vec![("sec-ch-viewport-width", "1024")], (Some(1024), None)),
+            (vec![("sec-ch-width", "600"), ("sec-ch-viewport-width", "1024")], (Some(600), None)),
+            (vec![("sec-ch-dpr", "lots"), ("sec-ch-width", "wide")], (None, None)),
+            (vec![], (None, None))
+        ];
+        for (hints, (width, dpr)) in cases {
+            let mut params = ImageParameters::default();
+            let consulted = apply_client_hints(&mut params, &hint_headers(&hints));
+            assert_eq!(params, ImageParameters { width, dpr, ..Default::default() });
+            assert_eq!(consulted, vec!["Sec-CH-DPR", "Sec-CH-Width", "Sec-CH-Viewport-Width"]);
+        }
+    }
+
+    #[test]
+    fn client_hints_dont_override_request() {
+        let mut params = ImageParameters { width: Some(200), dpr: Some(1.5), ..Default::default() };
+        let consulted = apply_client_hints(&mut params, &hint_headers(&[("sec-ch-dpr", "3"), ("sec-ch-width", "900")]));
+        assert_eq!(params, ImageParameters { width: Some(200), dpr: Some(1.5), ..Default::default() });
+        assert!(consulted.is_empty());
+
+        let mut params = ImageParameters { width: Some(200), ..Default::default() };
+        assert_eq!(apply_client_hints(&mut params, &HeaderMap::new()), vec!["Sec-CH-DPR"]);
+        let mut params = ImageParameters { dpr: Some(1.5), ..Default::default() };
+        assert_eq!(apply_client_hints(&mut params, &HeaderMap::new()), vec!["Sec-CH-Width", "Sec-CH-Viewport-Width"]);
+    }
+
+    // A 40x20 JPEG with an APP1 segment holding just an Orientation tag
+    fn jpeg_with_orientation(orientation: u8) -> Vec<u8> {
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(40, 20).write_to(&mut jpeg, image::ImageFormat::Jpeg).unwrap();
+        let jpeg = jpeg.into_inner();
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0]);
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xFF, 0xE1]);
+        bytes.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        bytes.extend_from_slice(&exif);
+        bytes.extend_from_slice(&jpeg[2..]);
+        bytes
+    }
+
+    #[test]
+    fn applies_exif_orientation_when_enabled() {
+        let cases = [(1, true, (40, 20)), (6, true, (20, 40)), (8, true, (20, 40)), (3, true, (40, 20)), (6, false, (40, 20))];
+        for (orientation, auto_orient, dimensions) in cases {
+            let settings = ImgprssrConfig { auto_orient, ..Default::default() };
+            let img = decode_image(&jpeg_with_orientation(orientation), image::ImageFormat::Jpeg, &settings).unwrap();
+            assert_eq!((img.width(), img.height()), dimensions);
+        }
+    }
+
+    #[test]
+    fn resolves_watermark_path() {
+        let mut settings = ImgprssrConfig {
+            watermark_image: Some("/logo.png".to_owned()),
+            watermark_path_prefixes: vec!["/products/".to_owned()],
+            ..Default::default()
+        };
+        let requested = ImageParameters { watermark: Some("/stamp.png".to_owned()), ..Default::default() };
+        let cases = [
+            (&requested, "/gallery/cat.png", Some("/stamp.png")),
+            (&requested, "/products/chair.png", Some("/stamp.png")),
+            (&ImageParameters::default(), "/products/chair.png", Some("/logo.png")),
+            (&ImageParameters::default(), "/gallery/cat.png", None)
+        ];
+        for (params, target_path, expected) in cases {
+            assert_eq!(watermark_path(&settings, params, target_path), expected);
+        }
+        settings.watermark_image = None;
+        assert_eq!(watermark_path(&settings, &ImageParameters::default(), "/products/chair.png"), None);
+    }
+
+    #[test]
+    fn expands_presets() {
+        let mut settings = ImgprssrConfig::default();
+        settings.presets.insert("thumb".to_owned(), "width=150&height=150".to_owned());
+        settings.presets.insert("hero".to_owned(), "width=1600".to_owned());
+        let cases = [
+            ("/test_card_sml.png", "width=10", Some(("/test_card_sml.png", "width=10".to_owned()))),
+            ("/test_card_sml.png", "preset=thumb", Some(("/test_card_sml.png", "width=150&height=150&preset=thumb".to_owned()))),
+            ("/_p/thumb/test_card_sml.png", "", Some(("/test_card_sml.png", "width=150&height=150".to_owned()))),
+            ("/_p/thumb/dir/test_card_sml.png", "height=100", Some(("/dir/test_card_sml.png", "width=150&height=150&height=100".to_owned()))),
+            // The query parameter wins over the path prefix
+            ("/_p/thumb/test_card_sml.png", "preset=hero", Some(("/test_card_sml.png", "width=1600&preset=hero".to_owned()))),
+            ("/_p/test_card_sml.png", "", Some(("/_p/test_card_sml.png", "".to_owned()))),
+            ("/test_card_sml.png", "preset=card", None),
+            ("/_p/card/test_card_sml.png", "", None)
+        ];
+        for (path, query, expected) in cases {
+            assert_eq!(apply_preset(&settings, path, query), expected);
+        }
+    }
+
+    #[test]
+    fn request_parameters_override_presets() {
+        let mut settings = ImgprssrConfig::default();
+        settings.presets.insert("thumb".to_owned(), "width=150&height=150".to_owned());
+        let (_, query) = apply_preset(&settings, "/_p/thumb/test_card_sml.png", "height=100").unwrap();
+        let params: ImageParameters = query.parse().unwrap();
+        assert_eq!(params, ImageParameters { width: Some(150), height: Some(100), ..Default::default() });
+    }
+
+    #[tokio::test]
+    async fn requires_valid_signatures_when_keyed() {
+        let settings = ImgprssrConfig { signing_key: Some("s3cret".to_owned()), ..Default::default() };
+        let signed = imgprssr::signing::sign_url(b"s3cret", "/test_card_sml.png", "width=100", None);
+        let expired = imgprssr::signing::sign_url(b"s3cret", "/test_card_sml.png", "width=100", Some(1));
+        let wrong_key = imgprssr::signing::sign_url(b"guessed", "/test_card_sml.png", "width=100", None);
+        let cases = [
+            ("/test_card_sml.png?width=100".to_owned(), SignatureError::MissingSignature),
+            (signed.replace("width=100", "width=200"), SignatureError::InvalidSignature),
+            (expired, SignatureError::Expired),
+            (wrong_key, SignatureError::InvalidSignature)
+        ];
+        for (uri, err) in cases {
+            let req = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+            assert_eq!(get_source_image(&settings, req).await.err(), Some(ImgprssrError::InvalidSignature(err)));
+        }
+        let req = hyper::Request::get(signed).body(hyper::Body::empty()).unwrap();
+        assert!(get_source_image(&settings, req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_hints_are_held_to_allowed_sizes() {
+        let settings = ImgprssrConfig { client_hints: true, allowed_widths: vec![200, 400, 800, 1280, 1600], ..Default::default() };
+        let hinted = |hints: &[(&'static str, &str)]| {
+            let mut req = hyper::Request::get("/test_card_sml.png").body(hyper::Body::empty()).unwrap();
+            *req.headers_mut() = hint_headers(hints);
+            req
+        };
+        let cases = [
+            (vec![("sec-ch-width", "1234")], None),
+            (vec![("sec-ch-viewport-width", "333")], None),
+            (vec![("sec-ch-width", "800")], Some(800)),
+            (vec![("sec-ch-dpr", "2.37")], None)
+        ];
+        for (hints, width) in cases {
+            let params = get_source_image(&settings, hinted(&hints)).await.unwrap().params;
+            assert_eq!(params.width, width, "{hints:?}");
+        }
+        let req = hyper::Request::get("/test_card_sml.png?width=200").header("sec-ch-dpr", "2.37").body(hyper::Body::empty()).unwrap();
+        let params = get_source_image(&settings, req).await.unwrap().params;
+        assert_eq!((params.width, params.dpr), (Some(200), None));
+
+        let settings = ImgprssrConfig { size_bucketing: true, ..settings };
+        let params = get_source_image(&settings, hinted(&[("sec-ch-width", "1300")])).await.unwrap().params;
+        assert_eq!(params.width, Some(1600));
+        let req = hyper::Request::get("/test_card_sml.png?width=150").header("sec-ch-dpr", "2.37").body(hyper::Body::empty()).unwrap();
+        let params = get_source_image(&settings, req).await.unwrap().params;
+        assert_eq!((params.width, params.dpr), (Some(800), Some(1.0)));
+    }
+
+    #[tokio::test]
+    async fn signed_requests_ignore_client_hints() {
+        let settings = ImgprssrConfig { signing_key: Some("s3cret".to_owned()), client_hints: true, ..Default::default() };
+        let signed = imgprssr::signing::sign_url(b"s3cret", "/test_card_sml.png", "", None);
+        let req = hyper::Request::get(signed)
+            .header("sec-ch-width", "1234")
+            .header("sec-ch-dpr", "3")
+            .body(hyper::Body::empty()).unwrap();
+        let sourced = get_source_image(&settings, req).await.unwrap();
+        assert_eq!(sourced.params, ImageParameters::default());
+        assert!(sourced.vary.is_empty());
+    }
+
+    #[test]
+    fn refuses_sources_over_the_limits() {
+        let cases = [
+            (ImgprssrConfig { max_source_width: 39, ..Default::default() }, false),
+            (ImgprssrConfig { max_source_height: 19, ..Default::default() }, false),
+            (ImgprssrConfig { max_source_pixels: 799, ..Default::default() }, false),
+            (ImgprssrConfig { max_source_width: 40, max_source_height: 20, max_source_pixels: 800, ..Default::default() }, true)
+        ];
+        for (settings, decodes) in cases {
+            let decoded = decode_image(&jpeg_with_orientation(1), image::ImageFormat::Jpeg, &settings);
+            assert_eq!(decoded.is_ok(), decodes);
+            if !decodes {
+                assert_eq!(decoded.err(), Some(ImgprssrError::SourceTooLarge));
+            }
+        }
+    }
+
+    // A PNG whose header claims 50000x50000, with no pixel data behind it
+    fn png_bomb() -> Vec<u8> {
+        let mut ihdr = 50000_u32.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&50000_u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(png_chunk(b"IHDR", &ihdr));
+        png.extend(png_chunk(b"IDAT", &[]));
+        png.extend(png_chunk(b"IEND", &[]));
+        png
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        let crc = png_crc(&chunk[4..]);
+        chunk.extend_from_slice(&crc.to_be_bytes());
+        chunk
+    }
+
+    fn png_crc(bytes: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFF_u32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn refuses_decompression_bombs_from_their_headers() {
+        let decoded = decode_image(&png_bomb(), image::ImageFormat::Png, &ImgprssrConfig::default());
+        assert_eq!(decoded.err(), Some(ImgprssrError::SourceTooLarge));
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_over_the_limits() {
+        let cases = [
+            (ImgprssrConfig { max_source_pixels: 100, ..Default::default() }, "/test_card_sml.png", ImgprssrError::SourceTooLarge),
+            (ImgprssrConfig { max_output_width: 100, ..Default::default() }, "/test_card_sml.png?width=101", ImgprssrError::OutputTooLarge),
+            (ImgprssrConfig { max_output_height: 100, ..Default::default() }, "/test_card_sml.png?width=100&pad=100", ImgprssrError::OutputTooLarge),
+            (ImgprssrConfig { allowed_widths: vec![200], ..Default::default() }, "/test_card_sml.png?crop=0,0,123,77", ImgprssrError::OutputSizeNotAllowed(123, 77))
+        ];
+        for (settings, uri, err) in cases {
+            let req = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+            assert_eq!(get_source_image(&settings, req).await.err(), Some(err));
+        }
+        let settings = ImgprssrConfig { max_output_width: 100, ..Default::default() };
+        let req = hyper::Request::get("/test_card_sml.png?width=100").body(hyper::Body::empty()).unwrap();
+        assert!(get_source_image(&settings, req).await.is_ok());
+    }
+
+    #[test]
+    fn relative_image_path_refuses_to_climb_out() {
+        let cases = [
+            ("/test_card_sml.png", Some("test_card_sml.png")),
+            ("//test_card_sml.png", Some("test_card_sml.png")),
+            ("/dir//./test_card_sml.png", Some("dir/test_card_sml.png")),
+            ("/my%20image.png", Some("my image.png")),
+            ("/../etc/passwd", None),
+            ("/dir/../../etc/passwd", None),
+            ("/%2e%2e/etc/passwd", None),
+            ("/%2E%2E/etc/passwd", None),
+            ("/.%2e/etc/passwd", None),
+            ("/%2e%2e%2fetc%2fpasswd", None),
+            ("/..%5cetc%5cpasswd", None),
+            ("/test_card_sml.png%00.jpg", None),
+            ("/%zz.png", None),
+            ("/%e9.png", None)
+        ];
+        for (target_path, expected) in cases {
+            assert_eq!(relative_image_path(target_path), expected.map(std::path::PathBuf::from));
+        }
+    }
+
+    // An image root inside a fresh temporary folder, holding an image and
+    // symlinks to one inside and one outside the root
+    #[cfg(unix)]
+    fn symlinked_root(name: &str) -> std::path::PathBuf {
+        let base = std::env::temp_dir().join(format!("imgprssr-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::copy("./images/test_card_sml.png", base.join("outside.png")).unwrap();
+        std::fs::copy("./images/test_card_sml.png", root.join("inside.png")).unwrap();
+        std::os::unix::fs::symlink(base.join("outside.png"), root.join("escape.png")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside.png"), root.join("alias.png")).unwrap();
+        root
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_source_stays_within_root() {
+        let root = symlinked_root("traversal");
+        let root = root.to_str().unwrap();
+        let settings = ImgprssrConfig::default();
+        let cases = [
+            ("/inside.png", true),
+            ("//inside.png", true),
+            ("/./inside.png", true),
+            ("/../outside.png", false),
+            ("/%2e%2e/outside.png", false),
+            ("/%2E%2e//outside.png", false),
+            ("//..//outside.png", false),
+            ("/%2e%2e%2foutside.png", false)
+        ];
+        for (target_path, found) in cases {
+            assert_eq!(source_image_from_file(root, target_path, &settings).is_ok(), found, "{target_path}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_source_follows_symlink_policy() {
+        use imgprssr::appconfig::SymlinkPolicy;
+        let root = symlinked_root("symlinks");
+        let root = root.to_str().unwrap();
+        let cases = [
+            (SymlinkPolicy::Never, "/alias.png", false),
+            (SymlinkPolicy::Never, "/escape.png", false),
+            (SymlinkPolicy::WithinRoot, "/alias.png", true),
+            (SymlinkPolicy::WithinRoot, "/escape.png", false),
+            (SymlinkPolicy::Always, "/alias.png", true),
+            (SymlinkPolicy::Always, "/escape.png", true)
+        ];
+        for (follow_symlinks, target_path, found) in cases {
+            let settings = ImgprssrConfig { follow_symlinks, ..Default::default() };
+            assert_eq!(source_image_from_file(root, target_path, &settings).is_ok(), found, "{target_path}");
+            assert!(source_image_from_file(root, "/inside.png", &settings).is_ok());
+            assert!(source_image_from_file(root, "/../outside.png", &settings).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn traversal_is_not_found() {
+        let req = hyper::Request::get("/%2e%2e/images/test_card_sml.png").body(hyper::Body::empty()).unwrap();
+        assert_eq!(get_source_image(&ImgprssrConfig::default(), req).await.err(), Some(ImgprssrError::NotFound));
+    }
+
+    async fn source_error(settings: &ImgprssrConfig, uri: &str) -> ImgprssrError {
+        let req = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+        get_source_image(settings, req).await.err().unwrap()
+    }
+
+    #[tokio::test]
+    async fn collects_every_invalid_parameter() {
+        let settings = ImgprssrConfig { allowed_heights: vec![100, 200], ..Default::default() };
+        assert_eq!(source_error(&settings, "/test_card_sml.png?width=wide&height=150&format=jxl").await, ImgprssrError::InvalidParameters(vec![
+            ImageParameterParseError::WidthParseError,
+            ImageParameterParseError::FormatParseError,
+            ImageParameterParseError::HeightNotAllowedError
+        ]));
+        assert_eq!(source_error(&settings, "/test_card_sml.png?preset=thumb").await, ImgprssrError::UnknownPreset);
+    }
+
+    // An image folder holding a truncated PNG, a JPEG named as a PNG,
+    // a text file, and an image with no extension at all
+    fn broken_images() -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("imgprssr-broken-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let png = std::fs::read("./images/test_card_sml.png").unwrap();
+        std::fs::write(root.join("truncated.png"), &png[..png.len() / 2]).unwrap();
+        std::fs::write(root.join("mislabelled.png"), jpeg_with_orientation(1)).unwrap();
+        std::fs::write(root.join("notes.txt"), "not an image").unwrap();
+        std::fs::write(root.join("no_extension"), &png).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn distinguishes_source_failures() {
+        let root = broken_images();
+        let settings = ImgprssrConfig { image_source: imgprssr::appconfig::ImgSource::Folder(root.to_str().unwrap().to_owned()), ..Default::default() };
+        assert_eq!(source_error(&settings, "/missing.png").await, ImgprssrError::NotFound);
+        assert_eq!(source_error(&settings, "/notes.txt").await, ImgprssrError::UnsupportedFormat);
+        assert_eq!(source_error(&settings, "/no_extension").await, ImgprssrError::UnsupportedFormat);
+        assert!(matches!(source_error(&settings, "/truncated.png").await, ImgprssrError::Decode(_)));
+        assert!(matches!(source_error(&settings, "/mislabelled.png").await, ImgprssrError::Decode(_)));
+    }
+
+    #[tokio::test]
+    async fn upstream_failures_are_distinguished() {
+        // Nothing listening once this is dropped
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let settings = ImgprssrConfig { image_source: imgprssr::appconfig::ImgSource::Http((hyper::Client::new(), format!("http://{refused}"))), ..Default::default() };
+        assert_eq!(source_error(&settings, "/test_card_sml.png").await, ImgprssrError::UpstreamFailed);
+
+        // Accepts connections but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = ImgprssrConfig {
+            image_source: imgprssr::appconfig::ImgSource::Http((hyper::Client::new(), format!("http://{}", silent.local_addr().unwrap()))),
+            upstream_timeout: 1,
+            ..Default::default()
+        };
+        assert_eq!(source_error(&settings, "/test_card_sml.png").await, ImgprssrError::UpstreamTimedOut);
+    }
+
+    #[tokio::test]
+    async fn odd_upstream_paths_are_not_found() {
+        let settings = ImgprssrConfig { image_source: imgprssr::appconfig::ImgSource::Http((hyper::Client::new(), "http://localhost:1".to_owned())), ..Default::default() };
+        let source = match &settings.image_source {
+            imgprssr::appconfig::ImgSource::Http(source) => source,
+            _ => unreachable!(),
+        };
+        assert_eq!(source_image_from_http(source, "/with space.png", &settings).await.err(), Some(ImgprssrError::NotFound));
+    }
+}